@@ -51,6 +51,7 @@ struct State<T> {
     pad2: [u8, ..64],
     dequeue_pos: AtomicUint,
     pad3: [u8, ..64],
+    dropped: AtomicUint,
 }
 
 pub struct Queue<T> {
@@ -81,6 +82,7 @@ impl<T: Send> State<T> {
             pad2: [0, ..64],
             dequeue_pos: AtomicUint::new(0),
             pad3: [0, ..64],
+            dropped: AtomicUint::new(0),
         }
     }
 
@@ -149,6 +151,25 @@ impl<T: Send> Queue<T> {
     pub fn pop(&mut self) -> Option<T> {
         unsafe { (*self.state.get()).pop() }
     }
+
+    /// Pushes a value on to the queue, or drops it if the queue is full.
+    ///
+    /// This is meant for best-effort pipelines (such as metrics) which would
+    /// rather lose data than fail or block. Each dropped value is counted, see
+    /// `dropped_count`.
+    pub fn push_lossy(&mut self, value: T) {
+        unsafe {
+            let state = self.state.get();
+            if !(*state).push(value) {
+                (*state).dropped.fetch_add(1, Relaxed);
+            }
+        }
+    }
+
+    /// Returns the number of values dropped by `push_lossy` on this queue.
+    pub fn dropped_count(&self) -> uint {
+        unsafe { (*self.state.get()).dropped.load(Relaxed) }
+    }
 }
 
 impl<T: Send> Clone for Queue<T> {
@@ -166,6 +187,23 @@ mod tests {
     use super::Queue;
     use native;
 
+    #[test]
+    fn lossy() {
+        let mut q = Queue::with_capacity(4);
+        for i in range(0, 10) {
+            q.push_lossy(i);
+        }
+        assert_eq!(q.dropped_count(), 6);
+        for i in range(0, 4) {
+            assert_eq!(q.pop(), Some(i));
+        }
+        assert_eq!(q.pop(), None);
+
+        q.push_lossy(10);
+        assert_eq!(q.pop(), Some(10));
+        assert_eq!(q.dropped_count(), 6);
+    }
+
     #[test]
     fn test() {
         let nthreads = 8u;
@@ -19,7 +19,7 @@
 #[doc(html_logo_url = "http://www.rust-lang.org/logos/rust-logo-128x128-blk-v2.png",
       html_favicon_url = "http://www.rust-lang.org/favicon.ico",
       html_root_url = "http://static.rust-lang.org/doc/master")];
-#[feature(phase)];
+#[feature(asm, phase)];
 
 #[allow(deprecated_owned_vector)];
 
@@ -27,7 +27,10 @@
 
 pub use arc::{Arc, MutexArc, RWArc, RWWriteMode, RWReadMode, ArcCondvar, CowArc};
 pub use sync::{Mutex, RWLock, Condvar, Semaphore, RWLockWriteMode,
-               RWLockReadMode, Barrier, one, mutex};
+               RWLockReadMode, RWLockUpgradableMode, Barrier, fence, lazy, one,
+               mutex, sharded, stack_chan, gauge, backpressure, sampler,
+               refcount, mpsc_intrusive, aligned, arena, bitset, bloom, credit,
+               notify, pinned, reclaim};
 pub use comm::{DuplexStream, SyncSender, SyncReceiver, rendezvous, duplex};
 pub use task_pool::TaskPool;
 pub use future::Future;
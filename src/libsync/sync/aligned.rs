@@ -0,0 +1,109 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Allocation of intrusive queue nodes on a chosen alignment.
+//!
+//! Nodes allocated as `~Node<T>` are only aligned as strictly as `Node<T>`
+//! requires. Some users need nodes which start on a cache line (to avoid false
+//! sharing with neighbouring allocations) or on a page boundary, and an
+//! `AlignedAlloc` hands out nodes which honor such a request.
+
+#[experimental];
+
+use std::cmp;
+use std::intrinsics;
+use std::libc;
+use std::mem;
+use std::ptr;
+use std::rt::global_heap;
+
+use sync::mpsc_intrusive::Node;
+
+/// An allocator of nodes aligned to a power-of-two boundary.
+///
+/// Nodes allocated through an `AlignedAlloc` must be freed through one.
+pub struct AlignedAlloc {
+    priv align: uint,
+}
+
+impl AlignedAlloc {
+    /// Creates an allocator whose nodes will start at a multiple of `align`,
+    /// which must be a power of two.
+    pub fn new(align: uint) -> AlignedAlloc {
+        assert!(align != 0 && align & (align - 1) == 0,
+                "alignment must be a power of two");
+        AlignedAlloc { align: align }
+    }
+
+    /// Returns the alignment which nodes from this allocator will have.
+    pub fn align(&self) -> uint { self.align }
+
+    /// Allocates a new node holding `t`.
+    pub unsafe fn alloc<T: Send>(&self, t: T) -> *mut Node<T> {
+        // The address of the underlying allocation is stashed in the word
+        // immediately preceding the node, so the allocation is padded with
+        // enough room for both that word and for moving the node up to the
+        // requested alignment.
+        let word = mem::size_of::<uint>();
+        let align = cmp::max(self.align,
+                             cmp::max(word, mem::min_align_of::<Node<T>>()));
+        let base = global_heap::malloc_raw(mem::size_of::<Node<T>>() +
+                                           word + align) as uint;
+        let addr = (base + word + align - 1) & !(align - 1);
+        *((addr - word) as *mut uint) = base;
+
+        let node = addr as *mut Node<T>;
+        intrinsics::move_val_init(&mut *node, Node::new(t));
+        node
+    }
+
+    /// Frees a node previously allocated by an `AlignedAlloc`, returning its
+    /// payload.
+    pub unsafe fn free<T: Send>(&self, node: *mut Node<T>) -> T {
+        let word = mem::size_of::<uint>();
+        let base = *((node as uint - word) as *uint);
+        let node = ptr::read(node as *Node<T>);
+        libc::free(base as *mut libc::c_void);
+        node.data
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AlignedAlloc;
+    use sync::mpsc_intrusive::{Queue, Data, Empty, Paused};
+
+    #[test]
+    fn honors_alignment() {
+        for &align in [1u, 8, 64, 128, 4096].iter() {
+            let a = AlignedAlloc::new(align);
+            let mut q = Queue::new();
+            for i in range(0u, 10) {
+                let node = unsafe { a.alloc(~i) };
+                assert_eq!(node as uint % align, 0);
+                unsafe { q.push(node); }
+            }
+            for i in range(0u, 10) {
+                match unsafe { q.pop() } {
+                    Data(node) => {
+                        assert_eq!(node as uint % align, 0);
+                        assert_eq!(unsafe { a.free(node) }, ~i);
+                    }
+                    Empty | Paused => fail!(),
+                }
+            }
+        }
+    }
+
+    #[test] #[should_fail]
+    fn not_a_power_of_two() {
+        AlignedAlloc::new(48);
+    }
+}
@@ -0,0 +1,117 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A bump-allocating arena of intrusive queue nodes.
+//!
+//! Workloads which push a large number of nodes through a queue and then
+//! discard them all at once can allocate their nodes from an `Arena` instead
+//! of allocating (and freeing) each node individually. Nodes are handed out
+//! from large chunks and are never freed one at a time; all of the chunks, and
+//! the payloads of every node allocated, are freed when the arena is dropped.
+
+#[experimental];
+
+use std::vec_ng::Vec;
+
+use sync::mpsc_intrusive::{Queue, Node};
+
+static DEFAULT_CHUNK_SIZE: uint = 1024;
+
+/// An arena of `Node<T>` slots.
+///
+/// It is up to the owner of the arena to guarantee that it outlives any queue
+/// which nodes allocated from it have been pushed on to, and that no node is
+/// freed by anything other than the arena itself.
+pub struct Arena<T> {
+    priv chunks: Vec<Vec<Node<T>>>,
+    priv chunk_size: uint,
+}
+
+impl<T: Send> Arena<T> {
+    /// Creates a new arena with a default chunk size.
+    pub fn new() -> Arena<T> {
+        Arena::with_chunk_size(DEFAULT_CHUNK_SIZE)
+    }
+
+    /// Creates a new arena which allocates `n` nodes at a time.
+    pub fn with_chunk_size(n: uint) -> Arena<T> {
+        assert!(n > 0);
+        Arena { chunks: Vec::new(), chunk_size: n }
+    }
+
+    /// Allocates a new node holding `t`, returning a pointer to it which is
+    /// valid for as long as this arena is alive.
+    pub fn alloc(&mut self, t: T) -> *mut Node<T> {
+        // Chunks are never grown past their initial capacity, so nodes never
+        // move once they've been allocated.
+        let full = match self.chunks.last() {
+            Some(chunk) => chunk.len() == chunk.capacity(),
+            None => true,
+        };
+        if full {
+            self.chunks.push(Vec::with_capacity(self.chunk_size));
+        }
+        let chunk = self.chunks.mut_last().unwrap();
+        chunk.push(Node::new(t));
+        let n = chunk.len();
+        chunk.get_mut(n - 1) as *mut Node<T>
+    }
+
+    /// Allocates a new node holding `t` and pushes it on to the queue
+    /// provided.
+    pub unsafe fn push(&mut self, q: &mut Queue<T>, t: T) {
+        q.push(self.alloc(t))
+    }
+
+    /// Returns the number of nodes which have been allocated from this arena.
+    pub fn len(&self) -> uint {
+        match self.chunks.last() {
+            Some(chunk) => (self.chunks.len() - 1) * self.chunk_size +
+                           chunk.len(),
+            None => 0,
+        }
+    }
+
+    /// Returns the number of chunks which have been allocated by this arena.
+    pub fn chunks(&self) -> uint { self.chunks.len() }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Arena;
+    use sync::mpsc_intrusive::{Queue, Data, Empty, Paused};
+    use sync::mpsc_intrusive::test::Drops;
+
+    #[test]
+    fn frees_on_drop() {
+        static N: uint = 100000;
+        let drops = Drops::new();
+        let mut arena = Arena::with_chunk_size(4096);
+        let mut q = Queue::new();
+        for i in range(0, N) {
+            unsafe { arena.push(&mut q, drops.payload(i)); }
+        }
+        assert_eq!(arena.len(), N);
+        assert_eq!(arena.chunks(), (N + 4095) / 4096);
+
+        for i in range(0, N) {
+            match unsafe { q.pop() } {
+                Data(node) => assert_eq!(unsafe { (*node).data.v }, i),
+                Empty | Paused => fail!(),
+            }
+        }
+        match unsafe { q.pop() } { Empty => {} _ => fail!() }
+
+        // nothing is freed until the arena goes away, and then everything is
+        assert_eq!(drops.count(), 0);
+        drop(arena);
+        assert_eq!(drops.count(), N);
+    }
+}
@@ -0,0 +1,127 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Spinning for short waits.
+//!
+//! Most of the waits in this crate are for another thread to finish a handful
+//! of instructions, such as a producer linking the node it has just pushed.
+//! Yielding to the OS for each of those is far more expensive than the wait
+//! itself, so spinning code should use a `Backoff`, which spins with the
+//! architecture's spin-wait hint for a few rounds before it starts yielding.
+//! It falls back to yielding so that a waiter can't starve a thread which was
+//! preempted in the middle of the operation being waited on.
+
+#[allow(dead_code)];
+
+use std::rt::thread::Thread;
+
+// The number of rounds of spinning, each twice as long as the last, before a
+// `Backoff` starts yielding the thread instead.
+static SPIN_LIMIT: uint = 6;
+
+/// Hints to the CPU that the current thread is in a spin-wait loop.
+///
+/// This is `pause` on x86, and nothing on other targets. ARM's `yield` is
+/// left out because it needs ARMv6K, and the ARM targets are built for older
+/// cores than that.
+#[cfg(target_arch = "x86")]
+#[cfg(target_arch = "x86_64")]
+#[inline]
+pub fn cpu_relax() {
+    unsafe { asm!("pause" :::: "volatile") }
+}
+
+/// Hints to the CPU that the current thread is in a spin-wait loop.
+#[cfg(not(target_arch = "x86"), not(target_arch = "x86_64"))]
+#[inline]
+pub fn cpu_relax() {}
+
+/// Exponential backoff for one spin-wait loop.
+pub struct Backoff {
+    priv step: uint,
+}
+
+impl Backoff {
+    /// Creates a backoff which will start by spinning.
+    pub fn new() -> Backoff { Backoff { step: 0 } }
+
+    /// Waits a little, spinning for twice as long as the previous call, or
+    /// yielding the thread once spinning has gone on for long enough.
+    pub fn snooze(&mut self) {
+        if self.step <= SPIN_LIMIT {
+            for _ in range(0, 1u << self.step) { cpu_relax() }
+            self.step += 1;
+        } else {
+            Thread::yield_now();
+        }
+    }
+
+    /// Returns whether the next call to `snooze` will spin rather than yield.
+    pub fn is_spinning(&self) -> bool { self.step <= SPIN_LIMIT }
+
+    /// Starts spinning again from the shortest wait, for when progress has
+    /// been made.
+    pub fn reset(&mut self) { self.step = 0 }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate native;
+
+    use std::sync::arc::UnsafeArc;
+    use std::sync::atomics;
+    use super::{Backoff, SPIN_LIMIT};
+
+    #[test]
+    fn spins_before_yielding() {
+        let mut b = Backoff::new();
+        for _ in range(0, SPIN_LIMIT + 1) {
+            assert!(b.is_spinning());
+            b.snooze();
+        }
+        assert!(!b.is_spinning());
+        b.snooze();
+        assert!(!b.is_spinning());
+        b.reset();
+        assert!(b.is_spinning());
+    }
+
+    #[test]
+    fn makes_progress() {
+        let flag = UnsafeArc::new(atomics::AtomicUint::new(0));
+        let (tx, rx) = channel();
+        let flag2 = flag.clone();
+        native::task::spawn(proc() {
+            // ping-pong with the main thread, each side spinning until the
+            // other has taken its turn
+            for i in range(0u, 100) {
+                let mut b = Backoff::new();
+                unsafe {
+                    while (*flag2.get()).load(atomics::SeqCst) != 2 * i + 1 {
+                        b.snooze();
+                    }
+                    (*flag2.get()).store(2 * i + 2, atomics::SeqCst);
+                }
+            }
+            tx.send(());
+        });
+
+        for i in range(0u, 100) {
+            let mut b = Backoff::new();
+            unsafe {
+                (*flag.get()).store(2 * i + 1, atomics::SeqCst);
+                while (*flag.get()).load(atomics::SeqCst) != 2 * i + 2 {
+                    b.snooze();
+                }
+            }
+        }
+        rx.recv();
+    }
+}
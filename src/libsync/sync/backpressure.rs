@@ -0,0 +1,71 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A backpressure signal from a queue's consumer to its producers.
+//!
+//! The consumer reports the depth of its queue as it goes, and producers check
+//! `should_backoff` before pushing. The signal is raised once the depth
+//! reaches a high watermark and is only cleared again once the depth falls to
+//! a low watermark, so a depth hovering around either threshold doesn't make
+//! the signal flap.
+
+use std::sync::atomics;
+
+/// A backoff flag with hysteresis between two watermarks.
+pub struct Backpressure {
+    priv low: uint,
+    priv high: uint,
+    priv backoff: atomics::AtomicBool,
+}
+
+impl Backpressure {
+    /// Creates a signal which is raised at a depth of `high` and cleared at a
+    /// depth of `low`, which must be lower.
+    pub fn new(low: uint, high: uint) -> Backpressure {
+        assert!(low < high);
+        Backpressure {
+            low: low,
+            high: high,
+            backoff: atomics::AtomicBool::new(false),
+        }
+    }
+
+    /// Reports the current depth of the queue. This should only be called by
+    /// the consumer.
+    pub fn update(&mut self, depth: uint) {
+        if depth >= self.high {
+            self.backoff.store(true, atomics::Relaxed);
+        } else if depth <= self.low {
+            self.backoff.store(false, atomics::Relaxed);
+        }
+    }
+
+    /// Returns whether producers should hold off on pushing.
+    pub fn should_backoff(&self) -> bool {
+        self.backoff.load(atomics::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Backpressure;
+
+    #[test]
+    fn hysteresis() {
+        let mut b = Backpressure::new(2, 5);
+        let trace = [(1u, false), (4, false), (5, true), (4, true), (3, true),
+                     (5, true), (6, true), (3, true), (2, false), (4, false),
+                     (3, false), (5, true), (1, false)];
+        for &(depth, expected) in trace.iter() {
+            b.update(depth);
+            assert_eq!(b.should_backoff(), expected);
+        }
+    }
+}
@@ -0,0 +1,184 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Benchmarks comparing the queues available to the primitives in this crate:
+//! the intrusive MPSC queue, and the SPSC and bounded MPMC queues from
+//! `std::sync`.
+//!
+//! Every iteration moves a fixed number of values through a queue, so the
+//! time reported per iteration divided by that number is the cost of one
+//! push/pop pair. The producer benchmarks run each producer on its own native
+//! thread while the benchmarking thread consumes, and move `THREADED_MSGS`
+//! values so that spawning and joining the producers each iteration is lost
+//! in the noise. The burst benchmarks push `MSGS` values before draining
+//! anything.
+
+extern crate test;
+extern crate native;
+
+use std::cast;
+use std::sync::arc::UnsafeArc;
+use std::sync::mpmc_bounded_queue;
+use std::sync::spsc_queue;
+use self::test::BenchHarness;
+
+use sync::mpsc_intrusive::{Queue, Node, Data, Empty, Paused};
+
+static MSGS: uint = 1000;
+static THREADED_MSGS: uint = 100_000;
+static BOUND: uint = 128;
+
+fn intrusive(producers: uint) {
+    let q: UnsafeArc<Queue<uint>> = UnsafeArc::new(Queue::new());
+    let (tx, rx) = channel();
+    for _ in range(0, producers) {
+        let q = q.clone();
+        let tx = tx.clone();
+        native::task::spawn(proc() {
+            for i in range(0, THREADED_MSGS / producers) {
+                unsafe {
+                    (*q.get()).push(cast::transmute(~Node::new(i)));
+                }
+            }
+            tx.send(());
+        });
+    }
+
+    let mut popped = 0;
+    while popped < THREADED_MSGS / producers * producers {
+        match unsafe { (*q.get()).pop() } {
+            Data(node) => {
+                let _: ~Node<uint> = unsafe { cast::transmute(node) };
+                popped += 1;
+            }
+            Empty | Paused => {}
+        }
+    }
+    for _ in range(0, producers) { rx.recv(); }
+}
+
+fn bounded(producers: uint) {
+    let mut q = mpmc_bounded_queue::Queue::with_capacity(BOUND);
+    let (tx, rx) = channel();
+    for _ in range(0, producers) {
+        let mut q = q.clone();
+        let tx = tx.clone();
+        native::task::spawn(proc() {
+            for i in range(0, THREADED_MSGS / producers) {
+                while !q.push(i) {}
+            }
+            tx.send(());
+        });
+    }
+
+    let mut popped = 0;
+    while popped < THREADED_MSGS / producers * producers {
+        if q.pop().is_some() { popped += 1; }
+    }
+    for _ in range(0, producers) { rx.recv(); }
+}
+
+fn spsc() {
+    let q = UnsafeArc::new(spsc_queue::Queue::new(BOUND));
+    let (tx, rx) = channel();
+    let q2 = q.clone();
+    native::task::spawn(proc() {
+        for i in range(0, THREADED_MSGS) {
+            unsafe { (*q2.get()).push(i); }
+        }
+        tx.send(());
+    });
+
+    let mut popped = 0;
+    while popped < THREADED_MSGS {
+        if unsafe { (*q.get()).pop() }.is_some() { popped += 1; }
+    }
+    rx.recv();
+}
+
+#[bench]
+fn intrusive_1_producer(bh: &mut BenchHarness) { bh.iter(|| intrusive(1)) }
+#[bench]
+fn intrusive_2_producers(bh: &mut BenchHarness) { bh.iter(|| intrusive(2)) }
+#[bench]
+fn intrusive_4_producers(bh: &mut BenchHarness) { bh.iter(|| intrusive(4)) }
+
+#[bench]
+fn intrusive_burst(bh: &mut BenchHarness) {
+    let mut q: Queue<uint> = Queue::new();
+    bh.iter(|| {
+        for i in range(0, MSGS) {
+            unsafe {
+                q.push(cast::transmute(~Node::new(i)));
+            }
+        }
+        for _ in range(0, MSGS) {
+            match unsafe { q.pop() } {
+                Data(node) => {
+                    let _: ~Node<uint> = unsafe { cast::transmute(node) };
+                }
+                Empty | Paused => fail!(),
+            }
+        }
+    })
+}
+
+fn drain(bh: &mut BenchHarness, prefetch: bool) {
+    let mut q: Queue<uint> = Queue::new();
+    bh.iter(|| {
+        for i in range(0, MSGS) {
+            unsafe {
+                q.push(cast::transmute(~Node::new(i)));
+            }
+        }
+        for _ in range(0, MSGS) {
+            match unsafe { q.pop() } {
+                Data(node) => {
+                    if prefetch { unsafe { q.prefetch_next(); } }
+                    let _: ~Node<uint> = unsafe { cast::transmute(node) };
+                }
+                Empty | Paused => fail!(),
+            }
+        }
+    })
+}
+
+#[bench]
+fn intrusive_drain(bh: &mut BenchHarness) { drain(bh, false) }
+#[bench]
+fn intrusive_drain_prefetch(bh: &mut BenchHarness) { drain(bh, true) }
+
+#[bench]
+fn bounded_1_producer(bh: &mut BenchHarness) { bh.iter(|| bounded(1)) }
+#[bench]
+fn bounded_2_producers(bh: &mut BenchHarness) { bh.iter(|| bounded(2)) }
+#[bench]
+fn bounded_4_producers(bh: &mut BenchHarness) { bh.iter(|| bounded(4)) }
+
+#[bench]
+fn bounded_burst(bh: &mut BenchHarness) {
+    let mut q = mpmc_bounded_queue::Queue::with_capacity(MSGS);
+    bh.iter(|| {
+        for i in range(0, MSGS) { assert!(q.push(i)); }
+        for _ in range(0, MSGS) { assert!(q.pop().is_some()); }
+    })
+}
+
+#[bench]
+fn spsc_1_producer(bh: &mut BenchHarness) { bh.iter(|| spsc()) }
+
+#[bench]
+fn spsc_burst(bh: &mut BenchHarness) {
+    let mut q = spsc_queue::Queue::new(BOUND);
+    bh.iter(|| {
+        for i in range(0, MSGS) { q.push(i); }
+        for _ in range(0, MSGS) { assert!(q.pop().is_some()); }
+    })
+}
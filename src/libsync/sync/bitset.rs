@@ -0,0 +1,165 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A fixed-size set of bits which can be set and cleared concurrently.
+//!
+//! A consumer of many queues can use one bit per queue to track which of them
+//! may have nodes on them, and so only visit those. Producers set a queue's
+//! bit when `push_was_empty` reports that they made it non-empty. The
+//! consumer clears the bit *before* draining the queue, since a push racing
+//! with the drain will then set it again. Once `pop` returns `Empty` it
+//! should also set the bit again if `is_empty` says otherwise, because the
+//! queue may be in an inconsistent state with a node left to pop.
+
+#[experimental];
+
+use std::num::Bitwise;
+use std::sync::atomics;
+use std::uint;
+use std::vec_ng::Vec;
+
+/// A set of `len` bits, all initially clear.
+pub struct AtomicBitset {
+    priv words: Vec<atomics::AtomicUint>,
+    priv len: uint,
+}
+
+impl AtomicBitset {
+    /// Creates a set of `len` clear bits.
+    pub fn new(len: uint) -> AtomicBitset {
+        let words = (len + uint::BITS - 1) / uint::BITS;
+        AtomicBitset {
+            words: range(0, words).map(|_| atomics::AtomicUint::new(0)).collect(),
+            len: len,
+        }
+    }
+
+    /// Returns the number of bits in the set.
+    pub fn len(&self) -> uint { self.len }
+
+    /// Sets bit `i`, returning whether it was previously set.
+    pub fn set(&mut self, i: uint) -> bool {
+        let (word, bit) = self.locate(i);
+        let word = self.words.get_mut(word);
+        let mut cur = word.load(atomics::SeqCst);
+        loop {
+            if cur & bit != 0 { return true }
+            let prev = word.compare_and_swap(cur, cur | bit, atomics::SeqCst);
+            if prev == cur { return false }
+            cur = prev;
+        }
+    }
+
+    /// Clears bit `i`, returning whether it was previously set.
+    pub fn clear(&mut self, i: uint) -> bool {
+        let (word, bit) = self.locate(i);
+        let word = self.words.get_mut(word);
+        let mut cur = word.load(atomics::SeqCst);
+        loop {
+            if cur & bit == 0 { return false }
+            let prev = word.compare_and_swap(cur, cur & !bit, atomics::SeqCst);
+            if prev == cur { return true }
+            cur = prev;
+        }
+    }
+
+    /// Returns whether bit `i` is set.
+    pub fn is_set(&self, i: uint) -> bool {
+        let (word, bit) = self.locate(i);
+        self.words.get(word).load(atomics::SeqCst) & bit != 0
+    }
+
+    /// Invokes `f` with the index of each set bit, in increasing order. Only
+    /// the words which have some bit set are scanned bit by bit.
+    ///
+    /// Bits set or cleared concurrently may or may not be visited.
+    pub fn each_set(&self, f: |uint|) {
+        for (w, word) in self.words.iter().enumerate() {
+            let mut bits = word.load(atomics::SeqCst);
+            while bits != 0 {
+                let bit = bits.trailing_zeros();
+                f(w * uint::BITS + bit);
+                bits &= bits - 1;
+            }
+        }
+    }
+
+    fn locate(&self, i: uint) -> (uint, uint) {
+        assert!(i < self.len);
+        (i / uint::BITS, 1 << (i % uint::BITS))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::vec_ng::Vec;
+    use super::AtomicBitset;
+    use sync::mpsc_intrusive::Queue;
+    use sync::mpsc_intrusive::test::{owned, pop_owned};
+
+    #[test]
+    fn set_clear() {
+        let mut b = AtomicBitset::new(130);
+        assert!(!b.set(0));
+        assert!(b.set(0));
+        assert!(!b.set(129));
+        assert!(b.is_set(129));
+        assert!(!b.is_set(64));
+        assert!(b.clear(0));
+        assert!(!b.clear(0));
+        let mut set = Vec::new();
+        b.each_set(|i| set.push(i));
+        assert!(set == Vec::from_slice(&[129u]));
+    }
+
+    #[test] #[should_fail]
+    fn out_of_bounds() {
+        let mut b = AtomicBitset::new(10);
+        b.set(10);
+    }
+
+    #[test]
+    fn visits_only_marked_shards() {
+        static SHARDS: uint = 200;
+        // the shards never move once the vector has been filled
+        let mut shards: Vec<Queue<uint>> = Vec::with_capacity(SHARDS);
+        for _ in range(0, SHARDS) { shards.push(Queue::new()); }
+        let mut marks = AtomicBitset::new(SHARDS);
+
+        for &(shard, v) in [(3u, 1u), (150, 2), (3, 3), (77, 4)].iter() {
+            if unsafe { shards.get_mut(shard).push_was_empty(owned(v)) } {
+                marks.set(shard);
+            }
+        }
+
+        let mut visited = Vec::new();
+        let mut popped = Vec::new();
+        let mut to_visit = Vec::new();
+        marks.each_set(|i| to_visit.push(i));
+        for &i in to_visit.iter() {
+            visited.push(i);
+            marks.clear(i);
+            let q = shards.get_mut(i);
+            loop {
+                match pop_owned(q) {
+                    Some(v) => popped.push(v),
+                    None => break,
+                }
+            }
+            if !unsafe { q.is_empty() } { marks.set(i); }
+        }
+        assert!(visited == Vec::from_slice(&[3u, 77, 150]));
+        assert!(popped == Vec::from_slice(&[1u, 3, 4, 2]));
+
+        let mut remaining = 0;
+        marks.each_set(|_| remaining += 1);
+        assert_eq!(remaining, 0);
+    }
+}
@@ -0,0 +1,151 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! An intrusive queue with a cheap, approximate membership test.
+//!
+//! `Queue::contains` has to walk the whole queue. A `FilteredQueue` keeps a
+//! counting Bloom filter of the nodes on it alongside the queue, which can say
+//! that a node is definitely not on the queue without walking anything. The
+//! filter is a set of counters indexed by hashes of the node's address: each
+//! push increments the node's counters before the node is visible to the
+//! consumer, and each pop decrements them afterwards. A node whose counters
+//! are all non-zero may be on the queue, and only then does the walk need to
+//! be done.
+
+#[experimental];
+
+use std::sync::atomics;
+use std::uint;
+use std::vec_ng::Vec;
+
+use sync::mpsc_intrusive::{Queue, Node, PopResult, Data};
+
+// Odd multipliers for the filter's hash functions, one per hash.
+static HASHES: [uint, ..3] = [0x9e3779b1, 0x85ebca6b, 0xc2b2ae35];
+
+/// An intrusive MPSC queue with a Bloom filter over the nodes on it.
+///
+/// Producers must push through `push` on this structure, and the consumer
+/// should pop through `pop`. Nodes popped directly off of the underlying
+/// queue are never removed from the filter, which only makes it report more
+/// false positives.
+pub struct FilteredQueue<T> {
+    priv queue: Queue<T>,
+    priv counts: Vec<atomics::AtomicUint>,
+}
+
+impl<T: Send> FilteredQueue<T> {
+    /// Creates a new queue whose filter has `slots` counters. More slots make
+    /// false positives less likely when there are many nodes on the queue.
+    pub fn new(slots: uint) -> FilteredQueue<T> {
+        assert!(slots > 0);
+        FilteredQueue {
+            queue: Queue::new(),
+            counts: range(0, slots).map(|_| atomics::AtomicUint::new(0)).collect(),
+        }
+    }
+
+    /// Pushes a node on to the queue, adding it to the filter.
+    pub unsafe fn push(&mut self, node: *mut Node<T>) {
+        self.adjust(node, true);
+        self.queue.push(node);
+    }
+
+    /// Pops a node off of the queue, removing it from the filter.
+    pub unsafe fn pop(&mut self) -> PopResult<T> {
+        let ret = self.queue.pop();
+        match ret {
+            Data(node) => self.adjust(node, false),
+            _ => {}
+        }
+        ret
+    }
+
+    /// Returns whether `node` may be on this queue.
+    ///
+    /// This never returns `false` for a node which has been pushed and not yet
+    /// popped, but may return `true` for a node which isn't on the queue.
+    pub fn maybe_contains(&self, node: *mut Node<T>) -> bool {
+        range(0, HASHES.len()).all(|i| {
+            self.counts.get(self.slot(node, i)).load(atomics::SeqCst) != 0
+        })
+    }
+
+    /// Returns whether `node` is on this queue, under the same conditions as
+    /// `Queue::contains`. The queue is only walked if the filter says that the
+    /// node may be on it.
+    pub unsafe fn contains(&self, node: *mut Node<T>) -> bool {
+        self.maybe_contains(node) && self.queue.contains(node)
+    }
+
+    /// Returns the underlying queue, for use by the consumer.
+    pub fn queue<'a>(&'a mut self) -> &'a mut Queue<T> { &mut self.queue }
+
+    fn adjust(&mut self, node: *mut Node<T>, add: bool) {
+        for i in range(0, HASHES.len()) {
+            let slot = self.slot(node, i);
+            let count = self.counts.get_mut(slot);
+            if add {
+                count.fetch_add(1, atomics::SeqCst);
+            } else {
+                count.fetch_sub(1, atomics::SeqCst);
+            }
+        }
+    }
+
+    fn slot(&self, node: *mut Node<T>, i: uint) -> uint {
+        // Nodes are at least word-aligned, so the low bits of their addresses
+        // carry nothing, and the high bits of a product are the best mixed.
+        let h = (node as uint >> 3) * HASHES[i];
+        (h >> uint::BITS / 2) % self.counts.len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::vec_ng::Vec;
+    use super::FilteredQueue;
+    use sync::mpsc_intrusive::{Node, Data, Empty, Paused};
+    use sync::mpsc_intrusive::test::{owned, free_owned};
+
+    #[test]
+    fn no_false_negatives() {
+        // a tiny filter, so that most lookups of absent nodes collide
+        for &slots in [8u, 4096].iter() {
+            let mut q: FilteredQueue<uint> = FilteredQueue::new(slots);
+            let nodes: Vec<*mut Node<uint>> =
+                range(0u, 200).map(|i| owned(i)).collect();
+
+            for &node in nodes.iter() {
+                assert!(!unsafe { q.contains(node) });
+                unsafe { q.push(node); }
+                assert!(q.maybe_contains(node));
+            }
+
+            for (i, &node) in nodes.iter().enumerate() {
+                match unsafe { q.pop() } {
+                    Data(n) => assert!(n == node),
+                    Empty | Paused => fail!(),
+                }
+                assert!(!unsafe { q.contains(node) });
+                for &rest in nodes.slice_from(i + 1).iter() {
+                    assert!(q.maybe_contains(rest));
+                    assert!(unsafe { q.contains(rest) });
+                }
+            }
+
+            // every counter is back to zero
+            for &node in nodes.iter() {
+                assert!(!q.maybe_contains(node));
+                free_owned(node);
+            }
+        }
+    }
+}
@@ -0,0 +1,119 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Consumer-paced flow control for an intrusive queue.
+//!
+//! Rather than bounding the number of nodes on the queue, a `CreditQueue` lets
+//! its consumer decide exactly how many more nodes it is willing to take by
+//! granting credits. Each push spends one credit, and producers wait while
+//! there are none left.
+
+#[experimental];
+
+use std::sync::atomics;
+
+use sync::backoff::Backoff;
+use sync::mpsc_intrusive::{TrackedQueue, Node, SendError};
+
+/// An intrusive MPSC queue which producers can only push on to while they
+/// have been granted credits.
+pub struct CreditQueue<T> {
+    priv queue: TrackedQueue<T>,
+    priv credits: atomics::AtomicUint,
+}
+
+impl<T: Send> CreditQueue<T> {
+    /// Creates a new queue with no credits granted.
+    pub fn new() -> CreditQueue<T> {
+        CreditQueue {
+            queue: TrackedQueue::new(),
+            credits: atomics::AtomicUint::new(0),
+        }
+    }
+
+    /// Grants producers `n` more pushes.
+    pub fn grant(&mut self, n: uint) {
+        self.credits.fetch_add(n, atomics::SeqCst);
+    }
+
+    /// Returns the number of credits which have yet to be spent.
+    pub fn credits(&self) -> uint { self.credits.load(atomics::SeqCst) }
+
+    /// Spends a credit and pushes a node on to the queue, yielding the current
+    /// thread until a credit is available.
+    ///
+    /// The node is handed back if the queue has been closed, including while
+    /// waiting for a credit. A credit spent on a rejected push is returned.
+    pub unsafe fn push(&mut self, node: *mut Node<T>)
+                       -> Result<(), SendError<T>> {
+        let mut backoff = Backoff::new();
+        loop {
+            if self.queue.is_closed() { return Err(SendError(node)) }
+            let cur = self.credits.load(atomics::SeqCst);
+            if cur == 0 {
+                backoff.snooze();
+            } else if self.credits.compare_and_swap(cur, cur - 1,
+                                                    atomics::SeqCst) == cur {
+                break
+            }
+        }
+        let ret = self.queue.push(node);
+        if ret.is_err() { self.grant(1); }
+        ret
+    }
+
+    /// Returns the underlying queue, for use by the consumer.
+    pub fn queue<'a>(&'a mut self) -> &'a mut TrackedQueue<T> {
+        &mut self.queue
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate native;
+    use std::rt::thread::Thread;
+    use std::sync::arc::UnsafeArc;
+    use super::CreditQueue;
+    use sync::mpsc_intrusive::test::{owned, pop_owned};
+
+    #[test]
+    fn pushes_only_with_credits() {
+        let q = UnsafeArc::new(CreditQueue::new());
+        let q2 = q.clone();
+        let (tx, rx) = channel();
+        native::task::spawn(proc() {
+            for i in range(0u, 10) {
+                unsafe { assert!((*q2.get()).push(owned(i)).is_ok()); }
+            }
+            tx.send(());
+        });
+
+        let mut next = 0u;
+        for &grant in [3u, 0, 5, 2].iter() {
+            unsafe { (*q.get()).grant(grant); }
+            let until = next + grant;
+            while next < until {
+                match pop_owned(unsafe { (*q.get()).queue().queue() }) {
+                    Some(v) => {
+                        assert_eq!(v, next);
+                        next += 1;
+                    }
+                    None => Thread::yield_now(),
+                }
+            }
+            // the producer is now out of credits, so nothing more arrives
+            for _ in range(0, 100) { Thread::yield_now(); }
+            assert_eq!(unsafe { (*q.get()).credits() }, 0);
+            assert!(pop_owned(unsafe { (*q.get()).queue().queue() }).is_none());
+        }
+        rx.recv();
+        assert_eq!(next, 10);
+    }
+}
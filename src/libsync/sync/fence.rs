@@ -0,0 +1,85 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Standalone memory fences.
+//!
+//! The primitives in this crate publish data with `Release` stores and observe
+//! it with `Acquire` loads. Structures built on top of them sometimes need to
+//! order plain memory accesses against those atomics without an atomic of
+//! their own to hang the ordering on, and these fences provide that. Routing
+//! all such fences through this module keeps them easy to find and audit.
+
+use std::sync::atomics;
+
+/// Issues a memory fence with the given ordering.
+///
+/// See `std::sync::atomics::fence` for the semantics of each ordering. Only
+/// `Acquire`, `Release`, `AcqRel` and `SeqCst` are accepted.
+#[inline]
+pub fn fence(ord: atomics::Ordering) {
+    atomics::fence(ord)
+}
+
+/// Issues an acquire fence.
+///
+/// Reads and writes after this fence can't be reordered before any read which
+/// precedes it. A relaxed load followed by an acquire fence synchronizes with
+/// the release store (or release fence) that the load observed.
+#[inline]
+pub fn acquire_fence() {
+    atomics::fence(atomics::Acquire)
+}
+
+/// Issues a release fence.
+///
+/// Reads and writes before this fence can't be reordered after any write which
+/// follows it, so data written before the fence is published by a subsequent
+/// relaxed store.
+#[inline]
+pub fn release_fence() {
+    atomics::fence(atomics::Release)
+}
+
+#[cfg(test)]
+mod test {
+    extern crate native;
+    use std::sync::arc::UnsafeArc;
+    use std::sync::atomics;
+    use std::rt::thread::Thread;
+    use super::{acquire_fence, release_fence};
+
+    #[test]
+    fn publishes_data() {
+        struct Shared { data: uint, ready: atomics::AtomicBool }
+        let shared = UnsafeArc::new(Shared {
+            data: 0,
+            ready: atomics::AtomicBool::new(false),
+        });
+        let s2 = shared.clone();
+        let (tx, rx) = channel();
+        native::task::spawn(proc() {
+            unsafe {
+                (*s2.get()).data = 42;
+                release_fence();
+                (*s2.get()).ready.store(true, atomics::Relaxed);
+            }
+            tx.send(());
+        });
+
+        unsafe {
+            while !(*shared.get()).ready.load(atomics::Relaxed) {
+                Thread::yield_now();
+            }
+            acquire_fence();
+            assert_eq!((*shared.get()).data, 42);
+        }
+        rx.recv();
+    }
+}
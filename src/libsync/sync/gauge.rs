@@ -0,0 +1,87 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A gauge tracking the extremes of a value, such as the peak depth of a
+//! queue, which any number of tasks may record samples in.
+
+use std::sync::atomics;
+use std::uint;
+
+/// The minimum and maximum of all of the values recorded so far.
+pub struct AtomicGauge {
+    priv min: atomics::AtomicUint,
+    priv max: atomics::AtomicUint,
+}
+
+impl AtomicGauge {
+    /// Creates a gauge with nothing recorded.
+    pub fn new() -> AtomicGauge {
+        AtomicGauge {
+            min: atomics::AtomicUint::new(uint::MAX),
+            max: atomics::AtomicUint::new(0),
+        }
+    }
+
+    /// Records the value `v`.
+    pub fn record(&mut self, v: uint) {
+        let mut cur = self.max.load(atomics::Relaxed);
+        while v > cur {
+            let prev = self.max.compare_and_swap(cur, v, atomics::Relaxed);
+            if prev == cur { break }
+            cur = prev;
+        }
+        let mut cur = self.min.load(atomics::Relaxed);
+        while v < cur {
+            let prev = self.min.compare_and_swap(cur, v, atomics::Relaxed);
+            if prev == cur { break }
+            cur = prev;
+        }
+    }
+
+    /// Returns the largest value recorded, or 0 if nothing has been.
+    pub fn max(&self) -> uint { self.max.load(atomics::Relaxed) }
+
+    /// Returns the smallest value recorded, or `uint::MAX` if nothing has
+    /// been.
+    pub fn min(&self) -> uint { self.min.load(atomics::Relaxed) }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate native;
+    use std::sync::arc::UnsafeArc;
+    use std::uint;
+    use super::AtomicGauge;
+
+    #[test]
+    fn concurrent_records() {
+        static NTHREADS: uint = 8;
+        let gauge = UnsafeArc::new(AtomicGauge::new());
+        assert_eq!(unsafe { (*gauge.get()).max() }, 0);
+        assert_eq!(unsafe { (*gauge.get()).min() }, uint::MAX);
+
+        let (tx, rx) = channel();
+        for t in range(0, NTHREADS) {
+            let gauge = gauge.clone();
+            let tx = tx.clone();
+            native::task::spawn(proc() {
+                for i in range(0u, 1000) {
+                    unsafe { (*gauge.get()).record(10 + i * NTHREADS + t); }
+                }
+                tx.send(());
+            });
+        }
+        for _ in range(0, NTHREADS) { rx.recv(); }
+        unsafe {
+            assert_eq!((*gauge.get()).max(), 10 + 999 * NTHREADS + NTHREADS - 1);
+            assert_eq!((*gauge.get()).min(), 10);
+        }
+    }
+}
@@ -0,0 +1,104 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A lazily initialized value
+//!
+//! This is built on top of `Once`, and it shares its caveats.
+
+use sync::one::{Once, ONCE_INIT};
+
+/// A value which is computed the first time that it is accessed, and cached
+/// from then on.
+///
+/// Like `Once`, this type blocks the calling *os thread* while another task is
+/// computing the value, so it should not be used from green tasks under
+/// normal circumstances.
+///
+/// # Example
+///
+/// ```rust
+/// use sync::lazy::Lazy;
+///
+/// let mut answer = Lazy::new(proc() { 6 * 7 });
+/// assert_eq!(*answer.get(), 42);
+/// ```
+pub struct Lazy<T> {
+    priv once: Once,
+    priv init: Option<proc() -> T>,
+    priv value: Option<T>,
+}
+
+impl<T: Send> Lazy<T> {
+    /// Creates a new lazy value which will be computed by `init`.
+    pub fn new(init: proc() -> T) -> Lazy<T> {
+        Lazy { once: ONCE_INIT, init: Some(init), value: None }
+    }
+
+    /// Returns the value, computing it first if this is the first access.
+    ///
+    /// If several tasks access the value at once, exactly one of them
+    /// computes it and the others wait for it to finish.
+    pub fn get<'a>(&'a mut self) -> &'a T {
+        {
+            let init = &mut self.init;
+            let value = &mut self.value;
+            self.once.doit(|| {
+                let init = init.take_unwrap();
+                *value = Some(init());
+            });
+        }
+        self.value.get_ref()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate native;
+    use std::sync::arc::UnsafeArc;
+    use std::sync::atomics;
+    use super::Lazy;
+
+    #[test]
+    fn smoke() {
+        let mut a = Lazy::new(proc() { ~"hello" });
+        assert_eq!(a.get().as_slice(), "hello");
+        assert_eq!(a.get().as_slice(), "hello");
+    }
+
+    #[test]
+    fn stampede() {
+        static NTHREADS: uint = 8;
+        static mut RUNS: atomics::AtomicUint = atomics::INIT_ATOMIC_UINT;
+
+        let lazy = UnsafeArc::new(Lazy::new(proc() {
+            unsafe { RUNS.fetch_add(1, atomics::SeqCst); }
+            ~42u
+        }));
+        let (tx, rx) = channel();
+        for _ in range(0, NTHREADS) {
+            let lazy = lazy.clone();
+            let tx = tx.clone();
+            native::task::spawn(proc() {
+                let value = unsafe { (*lazy.get()).get() };
+                tx.send((**value, &**value as *uint as uint));
+            });
+        }
+
+        let (value, addr) = rx.recv();
+        assert_eq!(value, 42);
+        for _ in range(1, NTHREADS) {
+            assert!(rx.recv() == (value, addr));
+        }
+        unsafe {
+            assert_eq!(&**(*lazy.get()).get() as *uint as uint, addr);
+            assert_eq!(RUNS.load(atomics::SeqCst), 1);
+        }
+    }
+}
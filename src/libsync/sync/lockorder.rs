@@ -0,0 +1,279 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Runtime lock-order checking for `Mutex` and `RWLock`.
+//!
+//! When compiled with `--cfg check_lock_order` (and without `--cfg ndebug`),
+//! each acquisition of a `Mutex` or `RWLock` consults the set of locks already
+//! held by the acquiring task. Acquiring lock B while holding lock A adds an
+//! edge A -> B to a global graph. If B can already reach A through that graph
+//! then the two locks have been acquired in opposite orders, which can
+//! deadlock, and the acquiring task fails with a report of the cycle instead.
+//!
+//! Locks are identified by a `LockId` handed out when they are created, and a
+//! lock's edges are removed from the graph when its id is dropped. Every
+//! nested acquisition still takes a global lock and searches the graph, so
+//! this is a debugging aid which must be opted into.
+
+use std::cast;
+use std::local_data;
+use std::sync::atomics;
+use std::vec_ng::Vec;
+
+use sync::mutex::{StaticMutex, MUTEX_INIT};
+
+// The stack of lock ids held by the current task, in acquisition order.
+local_data_key!(HELD: Vec<uint>)
+
+static mut NEXT_ID: atomics::AtomicUint = atomics::INIT_ATOMIC_UINT;
+
+// Guards EDGES, which is allocated on first use and never freed.
+static mut LOCK: StaticMutex = MUTEX_INIT;
+static mut EDGES: *mut Vec<(uint, uint)> = 0 as *mut Vec<(uint, uint)>;
+
+/// Returns a fresh id for a newly created lock.
+pub fn next_id() -> uint {
+    unsafe { NEXT_ID.fetch_add(1, atomics::SeqCst) + 1 }
+}
+
+/// Identifies a lock in the lock-order graph for as long as it exists.
+pub struct LockId {
+    priv id: uint,
+}
+
+impl LockId {
+    /// Returns a fresh id for a newly created lock.
+    pub fn new() -> LockId { LockId { id: next_id() } }
+
+    /// Returns the id to pass to `acquire`.
+    pub fn get(&self) -> uint { self.id }
+}
+
+impl Drop for LockId {
+    fn drop(&mut self) { forget(self.id) }
+}
+
+/// Marks the lock `id` as held by the current task until the guard is dropped.
+pub struct Held {
+    priv id: uint,
+}
+
+/// Records that the current task is about to acquire the lock `id`, failing
+/// if this would invert the order in which the locks it currently holds were
+/// previously acquired.
+pub fn acquire(id: uint) -> Held {
+    // The task must not fail while the held locks are loaned out, because
+    // the guards for those locks modify them as the task unwinds.
+    let inversion = local_data::get(HELD, |held| {
+        match held {
+            Some(held) if !held.is_empty() => unsafe {
+                record(held.as_slice(), id)
+            },
+            _ => None,
+        }
+    });
+    match inversion {
+        Some((h, cycle)) => {
+            let cycle: ~[~str] = cycle.iter().map(|id| id.to_str()).collect();
+            fail!("lock order inversion: acquiring lock {} while holding lock \
+                   {}, but locks were previously acquired in the order {}",
+                  id, h, cycle.connect(" -> "));
+        }
+        None => {}
+    }
+
+    local_data::modify(HELD, |held| {
+        let mut held = held.unwrap_or(Vec::new());
+        held.push(id);
+        Some(held)
+    });
+    Held { id: id }
+}
+
+// Adds an edge from each of the `held` locks to `id`, or returns the first
+// held lock which `id` can already reach, along with the path between them.
+unsafe fn record(held: &[uint], id: uint) -> Option<(uint, Vec<uint>)> {
+    let _g = LOCK.lock();
+    if EDGES.is_null() {
+        EDGES = cast::transmute(~Vec::<(uint, uint)>::new());
+    }
+    let edges = &mut *EDGES;
+    for &h in held.iter() {
+        if h == id { continue }
+        match path(edges.as_slice(), id, h) {
+            Some(cycle) => return Some((h, cycle)),
+            None => {
+                if !edges.contains(&(h, id)) {
+                    edges.push((h, id));
+                }
+            }
+        }
+    }
+    None
+}
+
+// Removes every edge to or from `id`, which will never be acquired again.
+fn forget(id: uint) {
+    unsafe {
+        let _g = LOCK.lock();
+        if EDGES.is_null() { return }
+        let edges = &mut *EDGES;
+        let mut i = 0;
+        while i < edges.len() {
+            let (a, b) = *edges.get(i);
+            if a == id || b == id {
+                edges.swap_remove(i);
+            } else {
+                i += 1;
+            }
+        }
+    }
+}
+
+impl Drop for Held {
+    fn drop(&mut self) {
+        local_data::modify(HELD, |held| {
+            held.map(|held| {
+                // Locks are almost always released in the reverse order they
+                // were acquired in, so only the most recent entry is removed.
+                let mut found = false;
+                let mut ret = Vec::with_capacity(held.len());
+                for &h in held.iter().rev() {
+                    if !found && h == self.id {
+                        found = true;
+                    } else {
+                        ret.push(h);
+                    }
+                }
+                ret.reverse();
+                ret
+            })
+        })
+    }
+}
+
+/// Finds a path from `from` to `to` through `edges`, if there is one.
+fn path(edges: &[(uint, uint)], from: uint, to: uint) -> Option<Vec<uint>> {
+    // (node, the node it was first reached from)
+    let mut parents = Vec::new();
+    let mut todo = Vec::new();
+    parents.push((from, from));
+    todo.push(from);
+    loop {
+        let cur = match todo.pop() {
+            Some(cur) => cur,
+            None => return None,
+        };
+        if cur == to { break }
+        for &(a, b) in edges.iter() {
+            if a == cur && !parents.iter().any(|&(n, _)| n == b) {
+                parents.push((b, cur));
+                todo.push(b);
+            }
+        }
+    }
+
+    let mut ret = Vec::new();
+    let mut cur = to;
+    ret.push(cur);
+    while cur != from {
+        for &(n, parent) in parents.iter() {
+            if n == cur { cur = parent; break }
+        }
+        ret.push(cur);
+    }
+    ret.reverse();
+    Some(ret)
+}
+
+#[cfg(test)]
+mod test {
+    use std::vec_ng::Vec;
+    use super::{path, next_id, acquire, LockId, LOCK, EDGES};
+
+    fn has_edge(from: uint, to: uint) -> bool {
+        unsafe {
+            let _g = LOCK.lock();
+            !EDGES.is_null() && (*EDGES).contains(&(from, to))
+        }
+    }
+
+    #[test]
+    fn finds_paths() {
+        let edges = &[(1u, 2u), (2, 3), (3, 4), (5, 1)];
+        assert!(path(edges, 1, 4).unwrap() == Vec::from_slice(&[1u, 2, 3, 4]));
+        assert!(path(edges, 5, 3).unwrap() == Vec::from_slice(&[5u, 1, 2, 3]));
+        assert!(path(edges, 4, 1).is_none());
+    }
+
+    #[test]
+    fn consistent_order() {
+        let (a, b, c) = (next_id(), next_id(), next_id());
+        for _ in range(0u, 3) {
+            let _a = acquire(a);
+            let _b = acquire(b);
+            let _c = acquire(c);
+        }
+        {
+            let _a = acquire(a);
+            let _c = acquire(c);
+        }
+        // re-acquiring a held lock is not an inversion
+        let _b = acquire(b);
+        let _b2 = acquire(b);
+    }
+
+    #[test]
+    fn released_locks_are_forgotten() {
+        let (a, b) = (next_id(), next_id());
+        drop(acquire(b));
+        let _a = acquire(a);
+        let _b = acquire(b);
+    }
+
+    #[test] #[should_fail]
+    fn detects_inversion() {
+        let (a, b) = (next_id(), next_id());
+        {
+            let _a = acquire(a);
+            let _b = acquire(b);
+        }
+        let _b = acquire(b);
+        let _a = acquire(a);
+    }
+
+    #[test] #[should_fail]
+    fn detects_transitive_inversion() {
+        let (a, b, c) = (next_id(), next_id(), next_id());
+        {
+            let _a = acquire(a);
+            let _b = acquire(b);
+        }
+        {
+            let _b = acquire(b);
+            let _c = acquire(c);
+        }
+        let _c = acquire(c);
+        let _a = acquire(a);
+    }
+
+    #[test]
+    fn forgets_dropped_locks() {
+        let (a, b) = (LockId::new(), LockId::new());
+        {
+            let _a = acquire(a.get());
+            let _b = acquire(b.get());
+        }
+        let (ia, ib) = (a.get(), b.get());
+        assert!(has_edge(ia, ib));
+        drop(b);
+        assert!(!has_edge(ia, ib));
+    }
+}
@@ -31,9 +31,29 @@ use arc::MutexArc;
  * Internals
  ****************************************************************************/
 
+pub mod aligned;
+pub mod arena;
+pub mod backpressure;
+pub mod bitset;
+pub mod bloom;
+pub mod credit;
+pub mod fence;
+pub mod gauge;
+pub mod lazy;
+pub mod mpsc_intrusive;
 pub mod mutex;
+pub mod notify;
 pub mod one;
-mod mpsc_intrusive;
+pub mod pinned;
+pub mod reclaim;
+pub mod refcount;
+pub mod sampler;
+pub mod sharded;
+pub mod stack_chan;
+mod backoff;
+mod lockorder;
+#[cfg(test)] mod bench;
+#[cfg(test)] mod recording;
 
 // Each waiting task receives on one of these.
 #[doc(hidden)]
@@ -93,6 +113,8 @@ impl WaitQueue {
 struct SemInner<Q> {
     lock: mutex::Mutex,
     count: int,
+    // Identifies this semaphore for lock-order checking, when enabled
+    id: Option<lockorder::LockId>,
     waiters:   WaitQueue,
     // Can be either unit or another waitqueue. Some sems shouldn't come with
     // a condition variable attached, others should.
@@ -106,6 +128,11 @@ impl<Q:Send> Sem<Q> {
     fn new(count: int, q: Q) -> Sem<Q> {
         Sem(UnsafeArc::new(SemInner {
             count: count,
+            id: if cfg!(check_lock_order, not(ndebug)) {
+                Some(lockorder::LockId::new())
+            } else {
+                None
+            },
             waiters: WaitQueue::new(),
             blocked: q,
             lock: mutex::Mutex::new(),
@@ -158,6 +185,17 @@ impl<Q:Send> Sem<Q> {
             self.release();
         })
     }
+
+    // Checks that acquiring this semaphore as a lock doesn't invert the order
+    // of any locks already held by this task. The returned value must be kept
+    // alive for as long as the lock is held.
+    fn check_order(&self) -> Option<lockorder::Held> {
+        let Sem(ref arc) = *self;
+        match unsafe { &(*arc.get()).id } {
+            &Some(ref id) => Some(lockorder::acquire(id.get())),
+            &None => None,
+        }
+    }
 }
 
 #[doc(hidden)]
@@ -176,6 +214,8 @@ impl Sem<~[WaitQueue]> {
 enum ReacquireOrderLock<'a> {
     Nothing, // c.c
     Just(&'a Semaphore),
+    // The second is acquired while holding the first
+    Both(&'a Semaphore, &'a Semaphore),
 }
 
 /// A mechanism for atomic-unlock-and-deschedule blocking and signalling.
@@ -249,6 +289,9 @@ impl<'a> Condvar<'a> {
                 // Reacquire the condvar.
                 match self.order {
                     Just(lock) => lock.access(|| self.sem.acquire()),
+                    Both(outer, inner) => outer.access(|| {
+                        inner.access(|| self.sem.acquire())
+                    }),
                     Nothing => self.sem.acquire(),
                 }
             })
@@ -415,12 +458,18 @@ impl Mutex {
 
 
     /// Run a function with ownership of the mutex.
+    ///
+    /// When lock-order checking is enabled with `--cfg check_lock_order`, this
+    /// fails if the mutex is being acquired in an order inconsistent with
+    /// other locks held by this task.
     pub fn lock<U>(&self, blk: || -> U) -> U {
+        let _held = (&self.sem).check_order();
         (&self.sem).access(blk)
     }
 
     /// Run a function with ownership of the mutex and a handle to a condvar.
     pub fn lock_cond<U>(&self, blk: |c: &Condvar| -> U) -> U {
+        let _held = (&self.sem).check_order();
         (&self.sem).access_cond(blk)
     }
 }
@@ -460,9 +509,11 @@ struct RWLockInner {
  * unwinds.
  */
 pub struct RWLock {
-    priv order_lock:  Semaphore,
-    priv access_lock: Sem<~[WaitQueue]>,
-    priv state:       UnsafeArc<RWLockInner>,
+    priv order_lock:   Semaphore,
+    priv access_lock:  Sem<~[WaitQueue]>,
+    priv state:        UnsafeArc<RWLockInner>,
+    // Held for the duration of an upgradable read
+    priv upgrade_lock: Semaphore,
 }
 
 impl RWLock {
@@ -478,17 +529,19 @@ impl RWLock {
             read_mode:  false,
             read_count: atomics::AtomicUint::new(0),
         });
-        RWLock { order_lock:  Semaphore::new(1),
-                access_lock: Sem::new_and_signal(1, num_condvars),
-                state:       state, }
+        RWLock { order_lock:   Semaphore::new(1),
+                access_lock:  Sem::new_and_signal(1, num_condvars),
+                state:        state,
+                upgrade_lock: Semaphore::new(1), }
     }
 
     /// Create a new handle to the rwlock.
     pub fn clone(&self) -> RWLock {
         let Sem(ref access_lock_queue) = self.access_lock;
-        RWLock { order_lock:  (&(self.order_lock)).clone(),
-                 access_lock: Sem(access_lock_queue.clone()),
-                 state:       self.state.clone() }
+        RWLock { order_lock:   (&(self.order_lock)).clone(),
+                 access_lock:  Sem(access_lock_queue.clone()),
+                 state:        self.state.clone(),
+                 upgrade_lock: (&(self.upgrade_lock)).clone() }
     }
 
     /**
@@ -496,6 +549,7 @@ impl RWLock {
      * tasks may run concurrently with this one.
      */
     pub fn read<U>(&self, blk: || -> U) -> U {
+        let _held = (&self.access_lock).check_order();
         unsafe {
             (&self.order_lock).access(|| {
                 let state = &mut *self.state.get();
@@ -529,9 +583,12 @@ impl RWLock {
      * 'write' from other tasks will run concurrently with this one.
      */
     pub fn write<U>(&self, blk: || -> U) -> U {
+        let _held = (&self.access_lock).check_order();
+        (&self.upgrade_lock).acquire();
         (&self.order_lock).acquire();
         (&self.access_lock).access(|| {
             (&self.order_lock).release();
+            (&self.upgrade_lock).release();
             blk()
         })
     }
@@ -569,10 +626,18 @@ impl RWLock {
         // which can't happen until T2 finishes the downgrade-read entirely.
         // The astute reader will also note that making waking writers use the
         // order_lock is better for not starving readers.
+        //
+        // Writers (including waking ones) also pass through upgrade_lock, so
+        // that none of them can be holding order_lock while waiting on an
+        // upgradable reader to leave: the upgradable reader may itself be
+        // trying to take order_lock in order to upgrade.
+        let _held = (&self.access_lock).check_order();
+        (&self.upgrade_lock).acquire();
         (&self.order_lock).acquire();
         (&self.access_lock).access_cond(|cond| {
             (&self.order_lock).release();
-            let opt_lock = Just(&self.order_lock);
+            (&self.upgrade_lock).release();
+            let opt_lock = Both(&self.upgrade_lock, &self.order_lock);
             blk(&Condvar { sem: cond.sem, order: opt_lock,
                            nopod: marker::NoPod })
         })
@@ -605,9 +670,12 @@ impl RWLock {
     pub fn write_downgrade<U>(&self, blk: |v: RWLockWriteMode| -> U) -> U {
         // Implementation slightly different from the slicker 'write's above.
         // The exit path is conditional on whether the caller downgrades.
+        let _held = (&self.access_lock).check_order();
+        (&self.upgrade_lock).acquire();
         (&self.order_lock).acquire();
         (&self.access_lock).acquire();
         (&self.order_lock).release();
+        (&self.upgrade_lock).release();
         (|| {
             blk(RWLockWriteMode { lock: self, nopod: marker::NoPod })
         }).finally(|| {
@@ -664,6 +732,37 @@ impl RWLock {
         }
         RWLockReadMode { lock: token.lock, nopod: marker::NoPod }
     }
+
+    /**
+     * Run a function with the rwlock in read mode, with the option of
+     * upgrading to write mode without letting any writer in between.
+     *
+     * The closure is passed a token which can be used to upgrade. Other
+     * readers may run concurrently with an upgradable reader, but only one
+     * upgradable reader runs at a time.
+     *
+     * # Example
+     *
+     * ```rust
+     * use sync::RWLock;
+     *
+     * let lock = RWLock::new();
+     * lock.upgradable_read(|token| {
+     *     // ... shared access, deciding whether to write ...
+     *     token.upgrade(|| {
+     *         // ... exclusive access ...
+     *     })
+     * })
+     * ```
+     */
+    pub fn upgradable_read<U>(&self, blk: |v: RWLockUpgradableMode| -> U)
+                              -> U {
+        (&self.upgrade_lock).access(|| {
+            self.read(|| {
+                blk(RWLockUpgradableMode { lock: self, nopod: marker::NoPod })
+            })
+        })
+    }
 }
 
 /// The "write permission" token used for rwlock.write_downgrade().
@@ -681,7 +780,8 @@ impl<'a> RWLockWriteMode<'a> {
         // Need to make the condvar use the order lock when reacquiring the
         // access lock. See comment in RWLock::write_cond for why.
         blk(&Condvar { sem:        &self.lock.access_lock,
-                       order: Just(&self.lock.order_lock),
+                       order: Both(&self.lock.upgrade_lock,
+                                   &self.lock.order_lock),
                        nopod: marker::NoPod })
     }
 }
@@ -691,6 +791,54 @@ impl<'a> RWLockReadMode<'a> {
     pub fn read<U>(&self, blk: || -> U) -> U { blk() }
 }
 
+/// The "upgrade permission" token used for rwlock.upgradable_read().
+pub struct RWLockUpgradableMode<'a> { priv lock: &'a RWLock,
+                                         priv nopod: marker::NoPod }
+
+impl<'a> RWLockUpgradableMode<'a> {
+    /// Access the pre-upgrade rwlock in read mode.
+    pub fn read<U>(&self, blk: || -> U) -> U { blk() }
+
+    /**
+     * Run a function with the rwlock upgraded to write mode, returning to
+     * read mode afterwards.
+     *
+     * This blocks until every other reader has left. Writers are held off for
+     * as long as an upgradable reader is running, and new readers from the
+     * moment the upgrade starts, so no writer can get in between the read and
+     * the write.
+     *
+     * No condvar is available in the upgraded block: a writer signalling it
+     * would never be able to get in.
+     */
+    pub fn upgrade<U>(&self, blk: || -> U) -> U {
+        let lock = self.lock;
+        unsafe {
+            // Holding order_lock keeps new readers and writers out while the
+            // other readers drain.
+            (&lock.order_lock).access(|| {
+                let state = &mut *lock.state.get();
+                assert!(state.read_mode);
+                let old_count = state.read_count.fetch_sub(1, atomics::Release);
+                assert!(old_count > 0);
+                if old_count == 1 {
+                    // We were the last reader, so the "reader cloud" access
+                    // lock is already held on our behalf.
+                    state.read_mode = false;
+                } else {
+                    // The last of the other readers releases access_lock.
+                    (&lock.access_lock).acquire();
+                }
+            });
+        }
+        blk.finally(|| {
+            // Return to read mode, so that the read in upgradable_read
+            // releases the lock as usual.
+            lock.downgrade(RWLockWriteMode { lock: lock, nopod: marker::NoPod });
+        })
+    }
+}
+
 /// A barrier enables multiple tasks to synchronize the beginning
 /// of some computation.
 ///
@@ -991,6 +1139,25 @@ mod tests {
         // child task must have finished by the time try returns
         m.lock(|| { })
     }
+    #[test] #[cfg(check_lock_order, not(ndebug))]
+    fn test_mutex_lock_order_inversion() {
+        use std::any::AnyOwnExt;
+
+        let a = Mutex::new();
+        let b = Mutex::new();
+        let (a2, b2) = (a.clone(), b.clone());
+        let result = task::try(proc() {
+            a2.lock(|| b2.lock(|| {}))
+        });
+        assert!(result.is_ok());
+
+        // Taking the same two locks the other way around could deadlock
+        let result = task::try(proc() {
+            b.lock(|| a.lock(|| {}))
+        });
+        let msg = result.unwrap_err().move::<~str>().unwrap();
+        assert!(msg.contains("lock order inversion"));
+    }
     #[test]
     fn test_mutex_cond_signal_on_0() {
         // Tests that signal_on(0) is equivalent to signal().
@@ -1027,7 +1194,8 @@ mod tests {
      * Reader/writer lock tests
      ************************************************************************/
     #[cfg(test)]
-    pub enum RWLockMode { Read, Write, Downgrade, DowngradeRead }
+    pub enum RWLockMode { Read, Write, Downgrade, DowngradeRead, Upgradable,
+                          Upgrade }
     #[cfg(test)]
     fn lock_rwlock_in_mode(x: &RWLock, mode: RWLockMode, blk: ||) {
         match mode {
@@ -1042,6 +1210,14 @@ mod tests {
                     let mode = x.downgrade(mode);
                     mode.read(|| { blk() });
                 }),
+            Upgradable =>
+                x.upgradable_read(|mode| {
+                    mode.read(|| { blk() });
+                }),
+            Upgrade =>
+                x.upgradable_read(|mode| {
+                    mode.upgrade(|| { blk() });
+                }),
         }
     }
     #[cfg(test)]
@@ -1146,6 +1322,25 @@ mod tests {
         test_rwlock_exclusion(&y, Write, Write);
     }
     #[test]
+    fn test_rwlock_upgrade() {
+        // Two upgradable readers never run at once, and an upgraded reader
+        // excludes everyone.
+        test_rwlock_exclusion(&RWLock::new(), Upgradable, Upgradable);
+        test_rwlock_exclusion(&RWLock::new(), Upgrade, Read);
+        test_rwlock_exclusion(&RWLock::new(), Read, Upgrade);
+        test_rwlock_exclusion(&RWLock::new(), Upgrade, Write);
+        test_rwlock_exclusion(&RWLock::new(), Write, Upgradable);
+        test_rwlock_exclusion(&RWLock::new(), Upgrade, Upgradable);
+        // But an upgradable reader shares with plain readers.
+        test_rwlock_handshake(&RWLock::new(), Upgradable, Read, false);
+        test_rwlock_handshake(&RWLock::new(), Read, Upgradable, false);
+        // And upgrading leaves the lock usable in both modes.
+        let x = RWLock::new();
+        lock_rwlock_in_mode(&x, Upgrade, || { });
+        test_rwlock_handshake(&x, Read, Read, false);
+        test_rwlock_exclusion(&x, Write, Write);
+    }
+    #[test]
     fn test_rwlock_read_recursive() {
         let x = RWLock::new();
         x.read(|| { x.read(|| { }) })
@@ -28,16 +28,30 @@
 //! A mostly lock-free multi-producer, single consumer queue.
 //!
 //! This module implements an intrusive MPSC queue. This queue is incredibly
-//! unsafe (due to use of unsafe pointers for nodes), and hence is not public.
+//! unsafe (due to use of unsafe pointers for nodes), so it and the wrappers
+//! built on it are exposed as experimental, for runtimes which need to manage
+//! their own nodes.
+
+#[experimental];
 
 // http://www.1024cores.net/home/lock-free-algorithms
 //                         /queues/intrusive-mpsc-node-based-queue
 
 use std::cast;
+use std::ops::{Deref, DerefMut};
 use std::sync::atomics;
+use std::vec_ng::Vec;
+
+use sync::Semaphore;
+use sync::backoff::Backoff;
 
 // NB: all links are done as AtomicUint instead of AtomicPtr to allow for static
-// initialization.
+// initialization. Links only ever point at nodes, never at payloads, so any
+// payload can be carried by value, including boxed trait objects.
+//
+// Storing pointers in an AtomicUint is fine because a uint is pointer-sized by
+// definition: the size of both is set by the target's word size, and there is
+// no way to build for a target on which they differ.
 
 pub struct Node<T> {
     next: atomics::AtomicUint,
@@ -52,6 +66,73 @@ pub struct Queue<T> {
     head: atomics::AtomicUint,
     tail: *mut Node<T>,
     stub: DummyNode,
+    paused: atomics::AtomicBool,
+    inconsistent: atomics::AtomicUint,
+}
+
+/// An intrusive MPSC queue which keeps track of the pushes in progress on to
+/// it, so that it can be closed, waited on, and drained through shutdown.
+///
+/// This bookkeeping costs every push two read-modify-writes of a shared
+/// counter on top of the swap which `Queue::push` does, plus a semaphore
+/// release whenever the push finds the queue empty, so it's kept out of the
+/// plain queue. Producers must push through this structure, while the
+/// consumer may pop from the underlying queue directly.
+pub struct TrackedQueue<T> {
+    priv queue: Queue<T>,
+    priv closed: atomics::AtomicBool,
+    // The number of pushes currently in progress
+    priv producers: atomics::AtomicUint,
+    // A decaying count of pushes which found the queue non-empty, in units
+    // of 1 / CONTENTION_ONE (see `contention_estimate`)
+    priv contention: atomics::AtomicUint,
+    // Released on each empty to non-empty transition and on closing, so that
+    // a `BlockingIter` can sleep while the queue is empty
+    priv wakeup: Semaphore,
+}
+
+// Each push keeps 1 - 2^-CONTENTION_DECAY of the previous estimate, so the
+// estimate covers roughly the last 2^CONTENTION_DECAY pushes.
+static CONTENTION_DECAY: uint = 4;
+static CONTENTION_ONE: uint = 1 << 8;
+
+/// A result of the `pop` function.
+pub enum PopResult<T> {
+    /// A node has been popped off the queue
+    Data(*mut Node<T>),
+    /// No node could be popped (see `pop` for why this may not mean empty)
+    Empty,
+    /// Consumption has been suspended through `pause`, and the queue was not
+    /// inspected at all.
+    Paused,
+}
+
+/// The error returned by a push on to a closed queue, carrying the node which
+/// was not pushed.
+pub struct SendError<T>(*mut Node<T>);
+
+/// A batch of owned nodes popped off of a queue.
+///
+/// Every node in the queue is assumed to have been allocated as a `~Node<T>`
+/// and transmuted to a raw pointer before being pushed. Nodes which have not
+/// been handed out through `take` by the time the batch goes out of scope are
+/// freed, so an early return while processing a batch does not leak.
+pub struct PopBatch<T> {
+    priv nodes: Vec<*mut Node<T>>,
+    priv taken: uint,
+}
+
+/// An owned node popped off of a queue, giving access to its payload in place
+/// and freeing the node when it goes out of scope. See `Queue::pop_guard`.
+pub struct PopGuard<T> {
+    priv node: *mut Node<T>,
+}
+
+/// An iterator over the payloads of owned nodes popped off of a queue, which
+/// blocks for more nodes whenever the queue is empty and ends once the queue
+/// has been closed and drained. See `TrackedQueue::blocking_iter`.
+pub struct BlockingIter<'a, T> {
+    priv q: &'a mut TrackedQueue<T>,
 }
 
 impl<T: Send> Queue<T> {
@@ -62,13 +143,292 @@ impl<T: Send> Queue<T> {
             stub: DummyNode {
                 next: atomics::AtomicUint::new(0),
             },
+            paused: atomics::AtomicBool::new(false),
+            inconsistent: atomics::AtomicUint::new(0),
         }
     }
 
+    /// Creates a queue holding `nodes`, in order, such that they will be popped
+    /// first to last.
+    ///
+    /// This is for rehydrating a queue from nodes which have already been
+    /// allocated, for example from a pool. A queue which nothing has been
+    /// popped from yet holds no pointers into itself, so it's fine to return
+    /// it by value.
+    pub unsafe fn from_nodes(nodes: Vec<*mut Node<T>>) -> Queue<T> {
+        let mut q = Queue::new();
+        for &node in nodes.iter() {
+            let prev = q.swap_head(node);
+            q.link(prev, node);
+        }
+        q
+    }
+
+    /// Suspends consumption of this queue. Until `resume` is called, `pop`
+    /// will return `Paused` without touching the list of nodes. Producers are
+    /// unaffected and may continue to push.
+    pub fn pause(&mut self) {
+        self.paused.store(true, atomics::Release);
+    }
+
+    /// Resumes consumption of a queue previously suspended with `pause`.
+    pub fn resume(&mut self) {
+        self.paused.store(false, atomics::Release);
+    }
+
+    /// Pushes a node on to this queue.
     pub unsafe fn push(&mut self, node: *mut Node<T>) {
+        let prev = self.swap_head(node);
+        self.link(prev, node);
+    }
+
+    /// Pushes a node on to this queue like `push`, additionally returning
+    /// whether the queue was empty before the push.
+    ///
+    /// A push onto a queue which the consumer is concurrently popping the last
+    /// node from may spuriously report that the queue was empty, but a push
+    /// onto an empty queue is never reported as a push onto a non-empty one.
+    pub unsafe fn push_was_empty(&mut self, node: *mut Node<T>) -> bool {
+        let prev = self.swap_head(node);
+        self.link(prev, node);
+        self.was_empty(prev)
+    }
+
+    /// Allocates `count` owned nodes holding `gen(0)` through
+    /// `gen(count - 1)`, and pushes them on to this queue as a single run, so
+    /// that they are popped in that order with no other pushes in between.
+    ///
+    /// The whole run is pushed with one swap of the head, no matter how long
+    /// it is.
+    pub unsafe fn push_generated(&mut self, count: uint, gen: |uint| -> T) {
+        if count == 0 { return }
+        let first: *mut Node<T> = cast::transmute(~Node::new(gen(0)));
+        let mut last = first;
+        for i in range(1, count) {
+            let node: *mut Node<T> = cast::transmute(~Node::new(gen(i)));
+            (*last).next.store(node as uint, atomics::Relaxed);
+            last = node;
+        }
+        let prev = self.swap_head(last);
+        self.link(prev, first);
+    }
+
+    /// Returns whether there are no nodes left for the consumer to pop.
+    ///
+    /// Pushes which are still in progress are not taken into account, so this
+    /// may return `true` even though some pusher has swapped itself in as the
+    /// head of the queue.
+    pub unsafe fn is_empty(&self) -> bool {
+        let stub = &self.stub as *DummyNode as uint;
+        let tail = self.tail as uint;
+        (tail == 0 || tail == stub) &&
+            self.stub.next.load(atomics::Acquire) == 0
+    }
+
+    /// Returns whether `node` is still on this queue, waiting to be popped.
+    ///
+    /// This walks every node on the queue, so it is only meant for debugging
+    /// (tracking down lost or doubly-freed nodes). It must only be called by
+    /// the consumer on a quiescent queue; while pushes are in progress the
+    /// answer is meaningless, though the walk itself stays in bounds.
+    pub unsafe fn contains(&self, node: *mut Node<T>) -> bool {
+        let stub = &self.stub as *DummyNode as uint;
+        let mut cur = if self.tail.is_null() { stub } else { self.tail as uint };
+        while cur != 0 {
+            if cur == node as uint { return true }
+            cur = if cur == stub {
+                self.stub.next.load(atomics::Acquire)
+            } else {
+                (*(cur as *mut Node<T>)).next.load(atomics::Acquire)
+            };
+        }
+        false
+    }
+
+    /// Hints to the CPU that the node after the one which `pop` will return
+    /// next is about to be read, so that draining a long chain of nodes
+    /// doesn't stall on a cache miss for every node.
+    ///
+    /// The intended call pattern is once per `pop`, right after it and before
+    /// the popped node is processed, which gives the prefetch the time spent
+    /// processing that node to land.
+    ///
+    /// This is only a hint, and it has no effect on targets with no prefetch
+    /// instruction. It must only be called by the consumer.
+    #[inline]
+    pub unsafe fn prefetch_next(&self) {
+        let stub = &self.stub as *DummyNode as uint;
+        let tail = self.tail as uint;
+        let first = if tail == 0 || tail == stub {
+            self.stub.next.load(atomics::Relaxed)
+        } else {
+            tail
+        };
+        if first == 0 { return }
+        let next = (*(first as *mut Node<T>)).next.load(atomics::Relaxed);
+        if next != 0 { prefetch(next) }
+    }
+
+    /// Pops a node off of a frozen queue, with no pushes in progress and none
+    /// to come until draining is done.
+    ///
+    /// Unlike `pop`, this never has to put the stub back on the queue: when
+    /// the last node is taken, the queue is simply reset to its initial state.
+    /// The result is the same sequence of nodes that `pop` would return, and
+    /// `None` where `pop` would return `Empty`. This ignores whether the queue
+    /// is paused.
+    pub unsafe fn drain_simple(&mut self) -> Option<*mut Node<T>> {
+        let stub = &self.stub as *DummyNode as uint;
+        let mut tail = self.tail;
+        if tail.is_null() || tail as uint == stub {
+            tail = self.stub.next.load(atomics::Relaxed) as *mut Node<T>;
+            if tail.is_null() { return None }
+        }
+        let next = (*tail).next(atomics::Relaxed);
+        if next.is_null() {
+            self.head.store(0, atomics::Relaxed);
+            self.tail = 0 as *mut Node<T>;
+            self.stub.next.store(0, atomics::Relaxed);
+        } else {
+            self.tail = next;
+        }
+        Some(tail)
+    }
+
+    /// Pops a node off of this queue like `pop`, returning a guard which frees
+    /// the node once it goes out of scope. The payload can be read and
+    /// modified through the guard without being moved out of the node.
+    ///
+    /// `None` is returned if `pop` would have returned `Empty` or `Paused`.
+    /// Every node in the queue is assumed to have been allocated as a
+    /// `~Node<T>`, as with `PopBatch`.
+    pub unsafe fn pop_guard(&mut self) -> Option<PopGuard<T>> {
+        match self.pop() {
+            Data(node) => Some(PopGuard { node: node }),
+            Empty | Paused => None,
+        }
+    }
+
+    /// Pops up to `max` owned nodes off of this queue, freeing them and
+    /// returning their payloads in FIFO order.
+    ///
+    /// As with `PopBatch`, popping stops early if the queue reports that it is
+    /// empty or paused, and every node is assumed to have been allocated as a
+    /// `~Node<T>`.
+    pub unsafe fn pop_batch_vec(&mut self, max: uint) -> Vec<T> {
+        let mut batch = PopBatch::new(self, max);
+        let mut ret = Vec::with_capacity(batch.len());
+        loop {
+            match batch.take() {
+                Some(node) => ret.push(node.data),
+                None => return ret,
+            }
+        }
+    }
+
+    /// Moves every node which was pushed on to this queue before this call on
+    /// to a new queue, and returns it. Producers carry on pushing on to this
+    /// queue, so the consumer can process the returned snapshot offline.
+    ///
+    /// Nodes pushed after the snapshot is taken are left on this queue, so
+    /// every node ends up on exactly one of the two queues, and this returns
+    /// no matter how quickly producers keep pushing. This ignores whether the
+    /// queue is paused, and it takes time linear in the number of nodes moved.
+    pub unsafe fn swap_empty(&mut self) -> Queue<T> {
+        // Nothing is ever popped off of `old` before it's returned, so it
+        // holds no pointers into itself and may be moved (see `from_nodes`).
+        let mut old = Queue::new();
+        self.pop_snapshot(|node| old.push(node));
+        old
+    }
+
+    /// Detaches every node which was pushed on to this queue before this call,
+    /// returning the first of them. The nodes are linked to one another in
+    /// FIFO order through their `next` links, ending in a null link, and the
+    /// stub is never among them.
+    ///
+    /// Once this returns the consumer can walk the detached nodes without
+    /// touching the live queue, which producers carry on pushing on to. As
+    /// with `swap_empty`, nodes pushed after the snapshot is taken are left on
+    /// the queue, so this returns however quickly producers keep pushing, and
+    /// this ignores whether the queue is paused. A null pointer is returned if
+    /// there was nothing to detach.
+    pub unsafe fn take_all(&mut self) -> *mut Node<T> {
+        let mut first = 0 as *mut Node<T>;
+        let mut last = 0 as *mut Node<T>;
+        self.pop_snapshot(|node| {
+            (*node).next.store(0, atomics::Relaxed);
+            if last.is_null() {
+                first = node;
+            } else {
+                (*last).next.store(node as uint, atomics::Relaxed);
+            }
+            last = node;
+        });
+        first
+    }
+
+    /// Pops every node which was pushed on to this queue before this call and
+    /// pushes them round-robin on to `workers`, so that the first node goes to
+    /// the first worker, the second to the second, and so on. Each worker
+    /// receives its share of the nodes in the order in which they were on
+    /// this queue.
+    ///
+    /// As with `swap_empty`, nodes pushed after the snapshot is taken are
+    /// left on this queue, and this ignores whether the queue is paused.
+    /// Returns the number of nodes which were moved.
+    pub unsafe fn redistribute(&mut self, workers: &mut [&mut Queue<T>])
+                               -> uint {
+        assert!(workers.len() > 0);
+        let mut moved = 0;
+        self.pop_snapshot(|node| {
+            workers[moved % workers.len()].push(node);
+            moved += 1;
+        });
+        moved
+    }
+
+    /// Returns the number of times that `pop` has observed the queue in an
+    /// inconsistent state (a push which has swapped the head but not yet
+    /// linked the previous node). This is only a statistic, and it's
+    /// maintained with relaxed atomics.
+    pub fn observed_inconsistencies(&self) -> uint {
+        self.inconsistent.load(atomics::Relaxed)
+    }
+
+    // Pops every node which was pushed before this call, handing each to `f`
+    // in FIFO order, and leaves everything pushed afterwards on the queue.
+    //
+    // The snapshot is bounded by pushing a marker, so that producers which
+    // keep pushing can't keep this from returning. The marker lives on this
+    // stack frame, so rather than stopping at an inconsistent state this
+    // waits for the pushes ahead of it to finish linking their nodes. A node
+    // is only ever popped once its successor has been linked to it, so once
+    // the marker is popped nothing refers to it any more.
+    unsafe fn pop_snapshot(&mut self, f: |*mut Node<T>|) {
+        let mut dummy = DummyNode { next: atomics::AtomicUint::new(0) };
+        let marker: *mut Node<T> = cast::transmute(&mut dummy);
+        self.push(marker);
+        let mut backoff = Backoff::new();
+        loop {
+            match self.pop_node() {
+                Data(node) if node == marker => return,
+                Data(node) => { f(node); backoff.reset(); }
+                Empty | Paused => backoff.snooze(),
+            }
+        }
+    }
+
+    // The two halves of `push`. Between these two calls the queue is in an
+    // inconsistent state.
+    #[inline]
+    unsafe fn swap_head(&mut self, node: *mut Node<T>) -> uint {
         (*node).next.store(0, atomics::Release);
-        let prev = self.head.swap(node as uint, atomics::AcqRel);
+        self.head.swap(node as uint, atomics::AcqRel)
+    }
 
+    #[inline]
+    unsafe fn link(&mut self, prev: uint, node: *mut Node<T>) {
         // Note that this code is slightly modified to allow static
         // initialization of these queues with rust's flavor of static
         // initialization.
@@ -80,6 +440,15 @@ impl<T: Send> Queue<T> {
         }
     }
 
+    // Returns whether a push which swapped out `prev` as the head found the
+    // queue empty. The head is only null before anything has ever been
+    // pushed, and otherwise the stub is only the head once the consumer has
+    // popped the last node.
+    #[inline]
+    fn was_empty(&self, prev: uint) -> bool {
+        prev == 0 || prev == &self.stub as *DummyNode as uint
+    }
+
     /// You'll note that the other MPSC queue in std::sync is non-intrusive and
     /// returns a `PopResult` here to indicate when the queue is inconsistent.
     /// An "inconsistent state" in the other queue means that a pusher has
@@ -91,9 +460,16 @@ impl<T: Send> Queue<T> {
     /// non-intrusive version.
     ///
     /// Right now consumers of this queue must be ready for this fact. Just
-    /// because `pop` returns `None` does not mean that there is not data
+    /// because `pop` returns `Empty` does not mean that there is not data
     /// on the queue.
-    pub unsafe fn pop(&mut self) -> Option<*mut Node<T>> {
+    pub unsafe fn pop(&mut self) -> PopResult<T> {
+        if self.paused.load(atomics::Acquire) {
+            return Paused;
+        }
+        self.pop_node()
+    }
+
+    unsafe fn pop_node(&mut self) -> PopResult<T> {
         let tail = self.tail;
         let mut tail = if !tail.is_null() {tail} else {
             cast::transmute(&self.stub)
@@ -101,7 +477,7 @@ impl<T: Send> Queue<T> {
         let mut next = (*tail).next(atomics::Relaxed);
         if tail as uint == &self.stub as *DummyNode as uint {
             if next.is_null() {
-                return None;
+                return Empty;
             }
             self.tail = next;
             tail = next;
@@ -109,20 +485,301 @@ impl<T: Send> Queue<T> {
         }
         if !next.is_null() {
             self.tail = next;
-            return Some(tail);
+            return Data(tail);
         }
         let head = self.head.load(atomics::Acquire) as *mut Node<T>;
         if tail != head {
-            return None;
+            self.inconsistent.fetch_add(1, atomics::Relaxed);
+            return Empty;
         }
         let stub = cast::transmute(&self.stub);
-        self.push(stub);
+        let prev = self.swap_head(stub);
+        self.link(prev, stub);
         next = (*tail).next(atomics::Relaxed);
         if !next.is_null() {
             self.tail = next;
-            return Some(tail);
+            return Data(tail);
+        }
+        self.inconsistent.fetch_add(1, atomics::Relaxed);
+        return Empty
+    }
+}
+
+impl<T: Send> TrackedQueue<T> {
+    pub fn new() -> TrackedQueue<T> {
+        TrackedQueue {
+            queue: Queue::new(),
+            closed: atomics::AtomicBool::new(false),
+            producers: atomics::AtomicUint::new(0),
+            contention: atomics::AtomicUint::new(0),
+            wakeup: Semaphore::new(0),
+        }
+    }
+
+    /// Pushes a node on to this queue.
+    ///
+    /// If the queue has been closed the node is not pushed, and it is handed
+    /// back to the caller in a `SendError` instead.
+    pub unsafe fn push(&mut self, node: *mut Node<T>)
+                       -> Result<(), SendError<T>> {
+        self.push_was_empty(node).map(|_| ())
+    }
+
+    /// Pushes a node on to this queue like `push`, additionally returning
+    /// whether the queue was empty before the push, as `Queue::push_was_empty`
+    /// does.
+    pub unsafe fn push_was_empty(&mut self, node: *mut Node<T>)
+                                 -> Result<bool, SendError<T>> {
+        // Announcing this push before checking for closure guarantees that a
+        // concurrent `close_into_vec` either sees this push in progress (and
+        // waits for it), or this push sees that the queue has been closed.
+        self.producers.fetch_add(1, atomics::SeqCst);
+        if self.closed.load(atomics::SeqCst) {
+            self.producers.fetch_sub(1, atomics::SeqCst);
+            return Err(SendError(node));
+        }
+        let was_empty = self.queue.push_was_empty(node);
+
+        // Concurrent updates may clobber one another, which is fine for an
+        // estimate.
+        let old = self.contention.load(atomics::Relaxed);
+        let mut new = old - (old >> CONTENTION_DECAY);
+        if !was_empty { new += CONTENTION_ONE; }
+        self.contention.store(new, atomics::Relaxed);
+
+        // A consumer which found the queue empty may be asleep, and nothing
+        // else will wake it for this node.
+        if was_empty { self.wakeup.release(); }
+
+        // Once the count of pushes in progress drops, the consumer may free
+        // the queue, so this must be the last access to it.
+        self.producers.fetch_sub(1, atomics::SeqCst);
+        Ok(was_empty)
+    }
+
+    /// Pops a node off of this queue, as `Queue::pop` does.
+    pub unsafe fn pop(&mut self) -> PopResult<T> { self.queue.pop() }
+
+    /// Returns an estimate of how many of the recent pushes onto this queue
+    /// found it already non-empty, out of the last 16 or so.
+    ///
+    /// A high estimate means that producers are outrunning the consumer (or
+    /// that many producers are pushing at once), and consumers may want to
+    /// pop in larger batches. This is only a statistic, and it's maintained
+    /// with relaxed atomics.
+    pub fn contention_estimate(&self) -> uint {
+        self.contention.load(atomics::Relaxed) / CONTENTION_ONE
+    }
+
+    /// Waits until the queue is empty and no pushes are in progress.
+    ///
+    /// This is intended for quiescence-based shutdown: once producers have
+    /// stopped pushing and the consumer has drained what it has seen, this
+    /// waits out any stragglers which were still linking their nodes. Only the
+    /// consumer can remove nodes, so this will not return for as long as there
+    /// are nodes left to pop.
+    ///
+    /// A straggler can't signal once it has finished, since the queue may be
+    /// freed as soon as its push is no longer counted, so this spins and then
+    /// yields the thread. It only waits for as long as pushes are mid-flight.
+    pub unsafe fn wait_empty(&mut self) {
+        let mut backoff = Backoff::new();
+        while self.producers.load(atomics::SeqCst) != 0 ||
+              !self.queue.is_empty() {
+            backoff.snooze();
+        }
+    }
+
+    /// Returns whether this queue has been closed.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(atomics::SeqCst)
+    }
+
+    /// Closes this queue, rejecting all further pushes. Nodes already on the
+    /// queue are left for the consumer to pop, and a consumer asleep in a
+    /// `BlockingIter` is woken up.
+    pub fn close(&mut self) {
+        self.closed.store(true, atomics::SeqCst);
+        self.wakeup.release();
+    }
+
+    /// Closes this queue, returning all of the nodes remaining on it in FIFO
+    /// order.
+    ///
+    /// Once this returns, every push which completed before the queue was
+    /// closed is accounted for in the returned vector, and all further pushes
+    /// are rejected. This ignores whether the queue is paused.
+    pub unsafe fn close_into_vec(&mut self) -> Vec<*mut Node<T>> {
+        self.closed.store(true, atomics::SeqCst);
+
+        // Pushes which raced with closing may still be linking their nodes,
+        // and until they're done the queue may look inconsistent.
+        let mut backoff = Backoff::new();
+        while self.producers.load(atomics::SeqCst) != 0 {
+            backoff.snooze();
+        }
+
+        let mut nodes = Vec::new();
+        loop {
+            match self.queue.pop_node() {
+                Data(node) => nodes.push(node),
+                Empty | Paused => break,
+            }
+        }
+        nodes
+    }
+
+    /// Returns an iterator which pops nodes off of this queue as they arrive,
+    /// yielding their payloads. The iterator only ends once the queue has been
+    /// closed and every node pushed before then has been yielded.
+    ///
+    /// While the queue is empty the iterator blocks the current task until a
+    /// push or `close` wakes it. It only spins, and then yields, while a push
+    /// is half-linked, while pushes which raced with closing finish, or while
+    /// the queue is paused.
+    ///
+    /// Every node in the queue is assumed to have been allocated as a
+    /// `~Node<T>`, as with `PopBatch`.
+    pub unsafe fn blocking_iter<'a>(&'a mut self) -> BlockingIter<'a, T> {
+        BlockingIter { q: self }
+    }
+
+    /// Pops nodes off of this queue as they arrive, handing their payloads to
+    /// `handler`, and returns once the queue has been closed and drained.
+    ///
+    /// This is how a consumer should shut down: when this returns, every push
+    /// which the queue accepted has been handled exactly once, and every push
+    /// from then on is rejected. As with `blocking_iter`, every node in the
+    /// queue is assumed to have been allocated as a `~Node<T>`.
+    pub unsafe fn run_until_closed(&mut self, handler: |T|) {
+        for t in self.blocking_iter() { handler(t) }
+    }
+
+    /// Returns the underlying queue, for use by the consumer.
+    pub fn queue<'a>(&'a mut self) -> &'a mut Queue<T> { &mut self.queue }
+}
+
+// Snapshots for replaying the same queue contents against different consumers
+// in tests. Like `contains`, these walk the chain and must only be used on a
+// quiescent queue, and nodes are assumed to be owned.
+#[cfg(test)]
+impl<T: Send + Clone> Queue<T> {
+    /// Returns a copy of every payload on this queue, in FIFO order.
+    pub unsafe fn checkpoint(&self) -> Vec<T> {
+        let stub = &self.stub as *DummyNode as uint;
+        let mut ret = Vec::new();
+        let mut cur = if self.tail.is_null() { stub } else { self.tail as uint };
+        while cur != 0 {
+            cur = if cur == stub {
+                self.stub.next.load(atomics::Acquire)
+            } else {
+                let node = cur as *mut Node<T>;
+                ret.push((*node).data.clone());
+                (*node).next.load(atomics::Acquire)
+            };
+        }
+        ret
+    }
+
+    /// Pushes freshly allocated owned nodes holding a copy of each payload in
+    /// `checkpoint` on to this queue, which must be empty.
+    pub unsafe fn restore(&mut self, checkpoint: &[T]) {
+        assert!(self.is_empty());
+        for t in checkpoint.iter() {
+            let node: *mut Node<T> = cast::transmute(~Node::new(t.clone()));
+            self.push(node);
+        }
+    }
+}
+
+impl<T: Send> SendError<T> {
+    /// Returns the node which was not pushed.
+    pub fn unwrap(self) -> *mut Node<T> {
+        let SendError(node) = self;
+        node
+    }
+}
+
+impl<T: Send> PopBatch<T> {
+    /// Pops up to `n` nodes off of the queue provided.
+    ///
+    /// Popping stops early if the queue reports that it is empty or paused, so
+    /// the batch may contain fewer than `n` nodes.
+    pub unsafe fn new(q: &mut Queue<T>, n: uint) -> PopBatch<T> {
+        // `n` is only an upper bound, so nothing is reserved up front: the
+        // vector grows as nodes are actually popped.
+        let mut nodes = Vec::new();
+        while nodes.len() < n {
+            match q.pop() {
+                Data(node) => nodes.push(node),
+                Empty | Paused => break,
+            }
+        }
+        PopBatch { nodes: nodes, taken: 0 }
+    }
+
+    /// Returns the number of nodes in this batch which have yet to be taken.
+    pub fn len(&self) -> uint { self.nodes.len() - self.taken }
+
+    /// Takes ownership of the next node in this batch, in FIFO order.
+    pub fn take(&mut self) -> Option<~Node<T>> {
+        if self.taken == self.nodes.len() { return None }
+        let node = *self.nodes.get(self.taken);
+        self.taken += 1;
+        Some(unsafe { cast::transmute(node) })
+    }
+}
+
+#[unsafe_destructor]
+impl<T: Send> Drop for PopBatch<T> {
+    fn drop(&mut self) {
+        while self.take().is_some() {}
+    }
+}
+
+impl<T: Send> Deref<T> for PopGuard<T> {
+    fn deref<'a>(&'a self) -> &'a T { unsafe { &(*self.node).data } }
+}
+
+impl<T: Send> DerefMut<T> for PopGuard<T> {
+    fn deref_mut<'a>(&'a mut self) -> &'a mut T {
+        unsafe { &mut (*self.node).data }
+    }
+}
+
+#[unsafe_destructor]
+impl<T: Send> Drop for PopGuard<T> {
+    fn drop(&mut self) {
+        let _: ~Node<T> = unsafe { cast::transmute(self.node) };
+    }
+}
+
+impl<'a, T: Send> Iterator<T> for BlockingIter<'a, T> {
+    fn next(&mut self) -> Option<T> {
+        let mut backoff = Backoff::new();
+        loop {
+            // Closure must be checked before popping: once the queue is
+            // closed and no pushes are in flight, an empty pop means that
+            // there's nothing left.
+            let closed = self.q.is_closed();
+            let done = closed && self.q.producers.load(atomics::SeqCst) == 0;
+            match unsafe { self.q.pop() } {
+                Data(node) => {
+                    let node: ~Node<T> = unsafe { cast::transmute(node) };
+                    return Some(node.data);
+                }
+                Empty if done => return None,
+                // Any push which finds the queue empty from here on releases
+                // the semaphore, as does closing. Once closed, the pushes
+                // still in flight can't signal when they finish, but they
+                // finish soon, so they're waited out below instead.
+                Empty if !closed && unsafe { self.q.queue.is_empty() } => {
+                    self.q.wakeup.acquire();
+                    backoff.reset();
+                }
+                Empty | Paused => backoff.snooze(),
+            }
         }
-        return None
     }
 }
 
@@ -137,3 +794,752 @@ impl<T: Send> Node<T> {
         cast::transmute::<uint, *mut Node<T>>(self.next.load(ord))
     }
 }
+
+#[cfg(target_arch = "x86")]
+#[cfg(target_arch = "x86_64")]
+#[inline]
+unsafe fn prefetch(addr: uint) {
+    asm!("prefetcht0 ($0)" :: "r"(addr) :: "volatile");
+}
+
+#[cfg(not(target_arch = "x86"), not(target_arch = "x86_64"))]
+#[inline]
+unsafe fn prefetch(_addr: uint) {}
+
+/// Wraps an existing box in a freshly allocated owned node, ready to be pushed.
+///
+/// Only the box itself is moved into the node, so the boxed value is never
+/// copied no matter how large it is.
+pub fn node_from_box<T: Send>(b: ~T) -> *mut Node<~T> {
+    unsafe { cast::transmute(~Node::new(b)) }
+}
+
+/// Frees an owned node previously created by `node_from_box` (or otherwise
+/// allocated as a `~Node<~T>`), returning the box it carried.
+pub unsafe fn box_from_node<T: Send>(node: *mut Node<~T>) -> ~T {
+    let node: ~Node<~T> = cast::transmute(node);
+    node.data
+}
+
+/// Frees a chain of owned nodes which was never pushed on to a queue, starting
+/// at `first` and following each node's link to the next until reaching a
+/// null link.
+///
+/// This is for cleaning up after abandoning a batch which a producer has
+/// linked together itself. The nodes must not be on any queue.
+pub unsafe fn free_chain<T: Send>(first: *mut Node<T>) {
+    let mut cur = first;
+    while !cur.is_null() {
+        let node: ~Node<T> = cast::transmute(cur);
+        cur = node.next.load(atomics::Relaxed) as *mut Node<T>;
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    extern crate native;
+    use std::cast;
+    use std::sync::arc::UnsafeArc;
+    use std::sync::atomics;
+    use std::uint;
+    use std::vec;
+    use std::vec_ng::Vec;
+    use super::{Queue, TrackedQueue, Node, PopBatch, SendError};
+    use super::{Data, Empty, Paused};
+    use super::{node_from_box, box_from_node, free_chain};
+
+    /// Allocates an owned node carrying `t`, ready to be pushed.
+    pub fn owned<T: Send>(t: T) -> *mut Node<T> {
+        unsafe { cast::transmute(~Node::new(t)) }
+    }
+
+    /// Frees a node allocated by `owned`, returning its payload.
+    pub fn free_owned<T: Send>(node: *mut Node<T>) -> T {
+        let node: ~Node<T> = unsafe { cast::transmute(node) };
+        node.data
+    }
+
+    /// Pops an owned node off of `q` and frees it, returning its payload, or
+    /// `None` if nothing could be popped.
+    pub fn pop_owned<T: Send>(q: &mut Queue<T>) -> Option<T> {
+        match unsafe { q.pop() } {
+            Data(node) => Some(free_owned(node)),
+            Empty | Paused => None,
+        }
+    }
+
+    /// Hands out payloads which count how many of them have been dropped.
+    pub struct Drops {
+        priv count: UnsafeArc<atomics::AtomicUint>,
+    }
+
+    /// A payload handed out by `Drops`.
+    pub struct Payload {
+        v: uint,
+        priv count: UnsafeArc<atomics::AtomicUint>,
+    }
+
+    impl Drops {
+        /// Creates a counter for which nothing has been dropped yet.
+        pub fn new() -> Drops {
+            Drops { count: UnsafeArc::new(atomics::AtomicUint::new(0)) }
+        }
+
+        /// Returns a new payload carrying `v`.
+        pub fn payload(&self, v: uint) -> Payload {
+            Payload { v: v, count: self.count.clone() }
+        }
+
+        /// Returns the number of payloads dropped so far.
+        pub fn count(&self) -> uint {
+            unsafe { (*self.count.get()).load(atomics::SeqCst) }
+        }
+    }
+
+    impl Drop for Payload {
+        fn drop(&mut self) {
+            unsafe { (*self.count.get()).fetch_add(1, atomics::SeqCst); }
+        }
+    }
+
+    #[test]
+    fn pop_batch_frees_untaken() {
+        fn consume(q: &mut Queue<Payload>) -> uint {
+            let mut batch = unsafe { PopBatch::new(q, 5) };
+            assert_eq!(batch.len(), 5);
+            for i in range(0u, 2) {
+                match batch.take() {
+                    Some(node) => assert_eq!(node.data.v, i),
+                    None => fail!(),
+                }
+            }
+            // the three remaining nodes are freed on the way out
+            return batch.len();
+        }
+
+        let drops = Drops::new();
+        let mut q = Queue::new();
+        for i in range(0u, 10) {
+            unsafe { q.push(owned(drops.payload(i))); }
+        }
+        assert_eq!(consume(&mut q), 3);
+        assert_eq!(drops.count(), 5);
+
+        // the rest of the queue was left untouched
+        for i in range(5u, 10) {
+            assert_eq!(pop_owned(&mut q).unwrap().v, i);
+        }
+        assert!(pop_owned(&mut q).is_none());
+        assert_eq!(drops.count(), 10);
+    }
+
+    #[test]
+    fn pop_guard() {
+        let drops = Drops::new();
+        let mut q = Queue::new();
+        unsafe {
+            q.push(owned(drops.payload(1)));
+            {
+                let mut guard = q.pop_guard().unwrap();
+                (*guard).v += 1;
+                assert_eq!((*guard).v, 2);
+                assert_eq!(drops.count(), 0);
+            }
+            assert_eq!(drops.count(), 1);
+            assert!(q.pop_guard().is_none());
+        }
+        assert_eq!(drops.count(), 1);
+    }
+
+    #[test]
+    fn frees_chain() {
+        let drops = Drops::new();
+        let mut first = 0 as *mut Node<Payload>;
+        for i in range(0u, 10) {
+            let node = owned(drops.payload(i));
+            unsafe { (*node).next.store(first as uint, atomics::Relaxed); }
+            first = node;
+        }
+        unsafe { free_chain(first); }
+        assert_eq!(drops.count(), 10);
+    }
+
+    #[test]
+    fn drain_simple() {
+        fn fill(q: &mut Queue<uint>, from: uint, to: uint) {
+            for i in range(from, to) {
+                unsafe { q.push(owned(i)); }
+            }
+        }
+        let mut a = Queue::new();
+        let mut b = Queue::new();
+        fill(&mut a, 0, 10);
+        fill(&mut b, 0, 10);
+        // drain both queues with `pop`, which puts the stub back on them
+        for i in range(0u, 10) {
+            assert_eq!(pop_owned(&mut a), Some(i));
+            assert_eq!(pop_owned(&mut b), Some(i));
+        }
+        fill(&mut a, 10, 20);
+        fill(&mut b, 10, 20);
+
+        loop {
+            match (pop_owned(&mut a), unsafe { b.drain_simple() }) {
+                (Some(x), Some(y)) => assert_eq!(x, free_owned(y)),
+                (None, None) => break,
+                _ => fail!(),
+            }
+        }
+
+        // a drained queue can be reused
+        fill(&mut b, 0, 2);
+        assert_eq!(pop_owned(&mut b), Some(0));
+        match unsafe { b.drain_simple() } {
+            Some(n) => assert_eq!(free_owned(n), 1),
+            None => fail!(),
+        }
+        assert!(unsafe { b.drain_simple() }.is_none());
+        assert!(pop_owned(&mut b).is_none());
+    }
+
+    #[test]
+    fn trait_objects() {
+        trait Job { fn run(&self) -> uint; }
+        struct Add(uint, uint);
+        impl Job for Add {
+            fn run(&self) -> uint { let Add(a, b) = *self; a + b }
+        }
+        struct Const(uint);
+        impl Job for Const {
+            fn run(&self) -> uint { let Const(a) = *self; a }
+        }
+
+        let mut q = Queue::new();
+        unsafe {
+            q.push(owned(~Add(1, 2) as ~Job:Send));
+            q.push(owned(~Const(7) as ~Job:Send));
+            q.push(owned(~Add(3, 4) as ~Job:Send));
+        }
+        for &expected in [3u, 7, 7].iter() {
+            assert_eq!(pop_owned(&mut q).unwrap().run(), expected);
+        }
+        assert!(pop_owned(&mut q).is_none());
+    }
+
+    #[test]
+    fn checkpoint_restore() {
+        fn drain(q: &mut Queue<~str>) -> ~[~str] {
+            let mut ret = ~[];
+            loop {
+                match pop_owned(q) {
+                    Some(s) => ret.push(s),
+                    None => return ret,
+                }
+            }
+        }
+
+        let mut q = Queue::new();
+        for i in range(0u, 3) {
+            unsafe { q.push(owned(i.to_str())); }
+        }
+        // move the stub into the chain before taking the checkpoint
+        assert_eq!(drain(&mut q).len(), 3);
+        for i in range(0u, 5) {
+            unsafe { q.push(owned(i.to_str())); }
+        }
+        assert!(pop_owned(&mut q).is_some());
+
+        let cp = unsafe { q.checkpoint() };
+        let first = drain(&mut q);
+        assert_eq!(first, ~[~"1", ~"2", ~"3", ~"4"]);
+        unsafe { q.restore(cp.as_slice()); }
+        assert_eq!(drain(&mut q), first);
+    }
+
+    #[test]
+    fn from_nodes() {
+        let mut nodes = Vec::new();
+        for i in range(0u, 10) { nodes.push(owned(i)); }
+        let expected = nodes.clone();
+
+        let mut q = unsafe { Queue::from_nodes(nodes) };
+        for &node in expected.iter() {
+            match unsafe { q.pop() } {
+                Data(n) => {
+                    assert!(n == node);
+                    free_owned(n);
+                }
+                Empty | Paused => fail!(),
+            }
+        }
+        assert!(pop_owned(&mut q).is_none());
+    }
+
+    #[test]
+    fn pop_batch_vec() {
+        let mut q = Queue::new();
+        for i in range(0u, 30) {
+            unsafe { q.push(owned(i)); }
+        }
+        for b in range(0u, 3) {
+            let batch = unsafe { q.pop_batch_vec(10) };
+            let expected: Vec<uint> = range(b * 10, b * 10 + 10).collect();
+            assert!(batch == expected);
+        }
+        assert!(unsafe { q.pop_batch_vec(10) }.is_empty());
+    }
+
+    #[test]
+    fn pop_batch_unbounded() {
+        let mut q = Queue::new();
+        for i in range(0u, 3) {
+            unsafe { q.push(owned(i)); }
+        }
+        let batch = unsafe { q.pop_batch_vec(uint::MAX) };
+        assert_eq!(batch.len(), 3);
+    }
+
+    #[test]
+    fn swap_empty() {
+        static NMSGS: uint = 1000;
+        fn drain(q: &mut Queue<uint>, seen: &mut [bool]) -> uint {
+            let mut last = None;
+            let mut n = 0;
+            loop {
+                let v = match pop_owned(q) { Some(v) => v, None => return n };
+                assert!(!seen[v]);
+                seen[v] = true;
+                assert!(last.map_or(true, |l| l < v));
+                last = Some(v);
+                n += 1;
+            }
+        }
+
+        let q = UnsafeArc::new(Queue::new());
+        let q2 = q.clone();
+        let (tx, rx) = channel();
+        native::task::spawn(proc() {
+            for i in range(0, NMSGS) {
+                unsafe { (*q2.get()).push(owned(i)); }
+            }
+            tx.send(());
+        });
+
+        let mut seen = vec::from_elem(NMSGS, false);
+        let mut total = 0;
+        while total < NMSGS {
+            let mut old = unsafe { (*q.get()).swap_empty() };
+            total += drain(&mut old, seen.as_mut_slice());
+            assert!(pop_owned(&mut old).is_none());
+        }
+        rx.recv();
+        assert_eq!(total, NMSGS);
+        assert!(seen.iter().all(|&b| b));
+    }
+
+    #[test]
+    fn swap_empty_is_bounded() {
+        // a producer which never lets up can't keep a snapshot from ending
+        let q = UnsafeArc::new(Queue::new());
+        let stop = UnsafeArc::new(atomics::AtomicBool::new(false));
+        let (q2, stop2) = (q.clone(), stop.clone());
+        let (tx, rx) = channel();
+        native::task::spawn(proc() {
+            let mut i = 0u;
+            while !unsafe { (*stop2.get()).load(atomics::SeqCst) } {
+                unsafe { (*q2.get()).push(owned(i)); }
+                i += 1;
+            }
+            tx.send(());
+        });
+
+        for _ in range(0, 100) {
+            let mut old = unsafe { (*q.get()).swap_empty() };
+            while pop_owned(&mut old).is_some() {}
+        }
+        unsafe { (*stop.get()).store(true, atomics::SeqCst); }
+        rx.recv();
+        while pop_owned(unsafe { &mut *q.get() }).is_some() {}
+    }
+
+    #[test]
+    fn take_all() {
+        let mut q = Queue::new();
+        for i in range(0u, 5) {
+            unsafe { q.push(owned(i)); }
+        }
+        assert_eq!(pop_owned(&mut q), Some(0));
+        for i in range(5u, 10) {
+            unsafe { q.push(owned(i)); }
+        }
+
+        let first = unsafe { q.take_all() };
+        assert!(unsafe { q.is_empty() });
+        unsafe { q.push(owned(10u)); }
+
+        let mut cur = first;
+        let mut expected = 1u;
+        while !cur.is_null() {
+            let node: ~Node<uint> = unsafe { cast::transmute(cur) };
+            assert_eq!(node.data, expected);
+            expected += 1;
+            cur = node.next.load(atomics::Relaxed) as *mut Node<uint>;
+        }
+        assert_eq!(expected, 10);
+
+        // the live queue kept going
+        assert_eq!(pop_owned(&mut q), Some(10));
+        assert!(pop_owned(&mut q).is_none());
+        assert!(unsafe { q.take_all() }.is_null());
+    }
+
+    #[test]
+    fn pause_resume() {
+        let mut q = Queue::new();
+        q.pause();
+        for i in range(0u, 3) {
+            unsafe { q.push(owned(i)); }
+        }
+        match unsafe { q.pop() } { Paused => {} _ => fail!() }
+        match unsafe { q.pop() } { Paused => {} _ => fail!() }
+
+        q.resume();
+        for i in range(0u, 3) {
+            assert_eq!(pop_owned(&mut q), Some(i));
+        }
+        match unsafe { q.pop() } { Empty => {} _ => fail!() }
+    }
+
+    #[test]
+    fn counts_inconsistencies() {
+        let mut a = Node::new(1u);
+        let mut b = Node::new(2u);
+        let a = &mut a as *mut Node<uint>;
+        let b = &mut b as *mut Node<uint>;
+        let mut q = Queue::new();
+        unsafe {
+            q.push(a);
+
+            // simulate a pusher which is preempted after swapping the head
+            let prev = q.swap_head(b);
+            for _ in range(0, 4) {
+                match q.pop() { Empty => {} _ => fail!() }
+            }
+            assert_eq!(q.observed_inconsistencies(), 4);
+
+            q.link(prev, b);
+            match q.pop() { Data(n) => assert!(n == a), _ => fail!() }
+            match q.pop() { Data(n) => assert!(n == b), _ => fail!() }
+            match q.pop() { Empty => {} _ => fail!() }
+        }
+        assert_eq!(q.observed_inconsistencies(), 4);
+    }
+
+    #[test]
+    fn close_into_vec() {
+        let mut q = TrackedQueue::new();
+        for i in range(0u, 5) {
+            unsafe { assert!(q.push(owned(i)).is_ok()); }
+        }
+        assert_eq!(pop_owned(q.queue()), Some(0));
+
+        let nodes = unsafe { q.close_into_vec() };
+        assert!(q.is_closed());
+        assert_eq!(nodes.len(), 4);
+        for (i, &node) in nodes.iter().enumerate() {
+            assert_eq!(free_owned(node), i + 1);
+        }
+
+        let node = owned(5u);
+        match unsafe { q.push(node) } {
+            Ok(()) => fail!(),
+            Err(SendError(n)) => {
+                assert!(n == node);
+                assert_eq!(free_owned(n), 5);
+            }
+        }
+        assert!(pop_owned(q.queue()).is_none());
+    }
+
+    #[test]
+    fn box_round_trip() {
+        let b = ~(1u, ~"two", 3u);
+        let addr = &*b as *(uint, ~str, uint) as uint;
+        let mut q = Queue::new();
+        unsafe {
+            q.push(node_from_box(b));
+            match q.pop() {
+                Data(node) => {
+                    let b = box_from_node(node);
+                    // the very same allocation comes back out
+                    assert_eq!(&*b as *(uint, ~str, uint) as uint, addr);
+                    assert!(*b == (1u, ~"two", 3u));
+                }
+                Empty | Paused => fail!(),
+            }
+        }
+    }
+
+    #[test]
+    fn contains() {
+        let mut q = Queue::new();
+        let a = owned(1u);
+        let b = owned(2u);
+        unsafe {
+            assert!(!q.contains(a));
+            q.push(a);
+            q.push(b);
+            assert!(q.contains(a));
+            assert!(q.contains(b));
+
+            match q.pop() { Data(n) => assert!(n == a), _ => fail!() }
+            assert!(!q.contains(a));
+            assert!(q.contains(b));
+            match q.pop() { Data(n) => assert!(n == b), _ => fail!() }
+            assert!(!q.contains(b));
+
+            free_owned(a);
+            free_owned(b);
+        }
+    }
+
+    #[test]
+    fn contention_estimate() {
+        fn pop(q: &mut TrackedQueue<uint>) {
+            assert!(pop_owned(q.queue()).is_some());
+        }
+
+        // every push finds the queue empty
+        let mut q = TrackedQueue::new();
+        for i in range(0u, 100) {
+            unsafe { assert!(q.push(owned(i)).is_ok()); }
+            pop(&mut q);
+        }
+        let low = q.contention_estimate();
+        assert_eq!(low, 0);
+
+        // every push but the first finds the queue non-empty
+        for i in range(0u, 100) {
+            unsafe { assert!(q.push(owned(i)).is_ok()); }
+        }
+        let high = q.contention_estimate();
+        assert!(high > low);
+        assert!(high <= 16);
+        for _ in range(0u, 100) { pop(&mut q); }
+
+        // and the estimate decays once pushes stop finding it non-empty
+        for i in range(0u, 100) {
+            unsafe { assert!(q.push(owned(i)).is_ok()); }
+            pop(&mut q);
+        }
+        assert!(q.contention_estimate() < high);
+    }
+
+    #[test]
+    fn blocking_iter() {
+        let q = UnsafeArc::new(TrackedQueue::new());
+        let q2 = q.clone();
+        native::task::spawn(proc() {
+            for i in range(0u, 100) {
+                unsafe { assert!((*q2.get()).push(owned(i)).is_ok()); }
+            }
+            unsafe { (*q2.get()).close(); }
+        });
+
+        let mut expected = 0;
+        for i in unsafe { (*q.get()).blocking_iter() } {
+            assert_eq!(i, expected);
+            expected += 1;
+        }
+        assert_eq!(expected, 100);
+        assert!(unsafe { (*q.get()).is_closed() });
+    }
+
+    #[test]
+    fn blocking_iter_wakes() {
+        // each push waits for the previous payload to be consumed, so the
+        // consumer keeps finding the queue empty and going to sleep
+        let q = UnsafeArc::new(TrackedQueue::new());
+        let q2 = q.clone();
+        let (tx, rx) = channel();
+        native::task::spawn(proc() {
+            for i in range(0u, 20) {
+                unsafe { assert!((*q2.get()).push(owned(i)).is_ok()); }
+                rx.recv();
+            }
+            unsafe { (*q2.get()).close(); }
+        });
+
+        let mut expected = 0;
+        for i in unsafe { (*q.get()).blocking_iter() } {
+            assert_eq!(i, expected);
+            expected += 1;
+            tx.send(());
+        }
+        assert_eq!(expected, 20);
+    }
+
+    #[test]
+    fn redistribute() {
+        let mut q = Queue::new();
+        for i in range(0u, 100) {
+            unsafe { q.push(owned(i)); }
+        }
+
+        let mut w0 = Queue::new();
+        let mut w1 = Queue::new();
+        let mut w2 = Queue::new();
+        let mut w3 = Queue::new();
+        q.pause();
+        unsafe {
+            let moved = q.redistribute(&mut [&mut w0, &mut w1, &mut w2, &mut w3]);
+            assert_eq!(moved, 100);
+            assert_eq!(q.redistribute(&mut [&mut w0]), 0);
+        }
+        q.resume();
+        assert!(pop_owned(&mut q).is_none());
+
+        let mut workers = [&mut w0, &mut w1, &mut w2, &mut w3];
+        for (n, w) in workers.mut_iter().enumerate() {
+            for i in range(0u, 25) {
+                assert_eq!(pop_owned(&mut **w), Some(n + 4 * i));
+            }
+            assert!(pop_owned(&mut **w).is_none());
+        }
+    }
+
+    #[test]
+    fn wait_empty() {
+        static NTHREADS: uint = 4;
+        static NMSGS: uint = 100;
+        let q = UnsafeArc::new(TrackedQueue::new());
+        assert!(unsafe { (*q.get()).queue().is_empty() });
+        let (tx, rx) = channel();
+        for _ in range(0, NTHREADS) {
+            let q = q.clone();
+            let tx = tx.clone();
+            native::task::spawn(proc() {
+                for i in range(0, NMSGS) {
+                    unsafe { assert!((*q.get()).push(owned(i)).is_ok()); }
+                }
+                tx.send(());
+            });
+        }
+        drop(tx);
+
+        let mut popped = 0;
+        while popped < NTHREADS * NMSGS {
+            if pop_owned(unsafe { (*q.get()).queue() }).is_some() {
+                popped += 1;
+            }
+        }
+        unsafe {
+            (*q.get()).wait_empty();
+            assert!((*q.get()).queue().is_empty());
+        }
+        for _ in range(0, NTHREADS) { rx.recv(); }
+    }
+
+    #[test]
+    fn push_generated() {
+        let mut q = Queue::new();
+        unsafe {
+            q.push(owned(1000u));
+            q.push_generated(100, |i| i * i);
+            q.push_generated(0, |_| fail!());
+            q.push(owned(1001u));
+        }
+
+        let mut popped = Vec::new();
+        loop {
+            match pop_owned(&mut q) {
+                Some(v) => popped.push(v),
+                None => break,
+            }
+        }
+        assert_eq!(popped.len(), 102);
+        assert_eq!(*popped.get(0), 1000);
+        for i in range(0u, 100) {
+            assert_eq!(*popped.get(i + 1), i * i);
+        }
+        assert_eq!(*popped.get(101), 1001);
+    }
+
+    #[test]
+    fn run_until_closed() {
+        static PRODUCERS: uint = 4;
+        static PUSHES: uint = 1000;
+
+        let q = UnsafeArc::new(TrackedQueue::new());
+        let (tx, rx) = channel();
+        for p in range(0, PRODUCERS) {
+            let q = q.clone();
+            let tx = tx.clone();
+            native::task::spawn(proc() {
+                let mut accepted = ~[];
+                for i in range(0, PUSHES) {
+                    let v = p * PUSHES + i;
+                    match unsafe { (*q.get()).push(owned(v)) } {
+                        Ok(()) => accepted.push(v),
+                        Err(e) => { free_owned(e.unwrap()); }
+                    }
+                    // the first producer shuts the queue down partway through
+                    if p == 0 && i == PUSHES / 2 {
+                        unsafe { (*q.get()).close(); }
+                    }
+                }
+                tx.send(accepted);
+            });
+        }
+
+        let mut seen = vec::from_elem(PRODUCERS * PUSHES, 0u);
+        unsafe {
+            (*q.get()).run_until_closed(|v| seen[v] += 1);
+            assert!(pop_owned((*q.get()).queue()).is_none());
+        }
+
+        let mut accepted = vec::from_elem(PRODUCERS * PUSHES, 0u);
+        for _ in range(0, PRODUCERS) {
+            for &v in rx.recv().iter() { accepted[v] += 1; }
+        }
+        assert!(accepted.iter().any(|&n| n == 0));
+        assert!(seen == accepted);
+    }
+
+    #[test]
+    fn per_producer_fifo() {
+        static PRODUCERS: uint = 4;
+        static PUSHES: uint = 1000;
+        let q = UnsafeArc::new(Queue::new());
+        let (tx, rx) = channel();
+        for p in range(0, PRODUCERS) {
+            let q = q.clone();
+            let tx = tx.clone();
+            native::task::spawn(proc() {
+                for i in range(0, PUSHES) {
+                    unsafe { (*q.get()).push(owned(p * PUSHES + i)); }
+                }
+                tx.send(());
+            });
+        }
+        drop(tx);
+
+        // each producer's payloads must come out in the order it pushed them,
+        // however they are interleaved with the other producers'
+        let mut next = vec::from_elem(PRODUCERS, 0u);
+        let mut popped = 0;
+        while popped < PRODUCERS * PUSHES {
+            match pop_owned(unsafe { &mut *q.get() }) {
+                Some(v) => {
+                    let (p, i) = (v / PUSHES, v % PUSHES);
+                    assert_eq!(i, next[p]);
+                    next[p] += 1;
+                    popped += 1;
+                }
+                None => {}
+            }
+        }
+        assert!(next.iter().all(|&n| n == PUSHES));
+        for _ in range(0, PRODUCERS) { rx.recv(); }
+    }
+}
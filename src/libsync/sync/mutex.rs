@@ -59,10 +59,10 @@
 
 use std::rt::local::Local;
 use std::rt::task::{BlockedTask, Task};
-use std::rt::thread::Thread;
 use std::sync::atomics;
 use std::unstable::mutex;
 
+use sync::backoff::Backoff;
 use q = sync::mpsc_intrusive;
 
 pub static LOCKED: uint = 1 << 0;
@@ -162,7 +162,9 @@ pub static MUTEX_INIT: StaticMutex = StaticMutex {
         tail: 0 as *mut q::Node<uint>,
         stub: q::DummyNode {
             next: atomics::INIT_ATOMIC_UINT,
-        }
+        },
+        paused: atomics::INIT_ATOMIC_BOOL,
+        inconsistent: atomics::INIT_ATOMIC_UINT,
     }
 };
 
@@ -323,10 +325,11 @@ impl StaticMutex {
         // otherwise the fixme above forces us to spin for a bit.
         if self.green_cnt.fetch_sub(1, atomics::SeqCst) == 1 { return }
         let node;
+        let mut backoff = Backoff::new();
         loop {
             match unsafe { self.q.pop() } {
-                Some(t) => { node = t; break; }
-                None => Thread::yield_now(),
+                q::Data(t) => { node = t; break; }
+                q::Empty | q::Paused => backoff.snooze(),
             }
         }
         let task = unsafe { BlockedTask::cast_from_uint((*node).data) };
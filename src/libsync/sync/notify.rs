@@ -0,0 +1,339 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! An intrusive queue which notifies its consumer when data arrives.
+//!
+//! Consumers driven by an external event loop can't block in `pop`, but they
+//! need to find out when there is something to pop. A `NotifyQueue` invokes a
+//! callback whenever a push takes the queue from empty to non-empty, which can
+//! be used to signal the event loop.
+//!
+//! A runtime with many queues can use a `ConsumerContext` instead, which
+//! carries the runtime's own data for the consumer (such as the reactor slot
+//! to wake) and hands it to the callback, so that no separate map from queues
+//! to consumers needs to be maintained.
+//!
+//! On Linux, the wakeup can also be delivered through an eventfd, which an
+//! event loop can wait on with epoll alongside its other descriptors.
+//!
+//! # Waiting for a wakeup
+//!
+//! A push which races with the consumer popping the last node off the queue
+//! sees the queue as non-empty, and so does not invoke the callback, yet the
+//! consumer's `pop` may still return `Empty` because that push is not yet
+//! linked in. A consumer must therefore only go back to waiting for a wakeup
+//! once `pop` has returned `Empty` *and* `queue().is_empty()` returns `true`.
+//! If `is_empty` returns `false` a push is in progress, and the consumer
+//! should retry `pop` (optionally after yielding) instead of waiting, or the
+//! wakeup for that node may never arrive.
+
+#[experimental];
+
+#[cfg(target_os = "linux")] use std::libc;
+#[cfg(target_os = "linux")] use std::os;
+
+use sync::mpsc_intrusive::{Queue, Node, PopResult};
+
+/// A callback invoked when a `NotifyQueue` becomes non-empty.
+///
+/// The callback is run on the pushing task, so it should be quick.
+pub trait Wake {
+    fn wake(&self);
+}
+
+/// A callback invoked when a `ConsumerContext` becomes non-empty, which is
+/// given the consumer's context.
+pub trait WakeWith<C> {
+    fn wake(&self, context: &C);
+}
+
+/// An intrusive MPSC queue with an optional wakeup callback.
+///
+/// Producers must push through `push` on this structure for the callback to be
+/// invoked. The consumer has full access to the underlying queue, and must
+/// follow the protocol in the module documentation before waiting for the
+/// callback to fire.
+pub struct NotifyQueue<T> {
+    priv queue: Queue<T>,
+    priv wake: Option<~Wake:Send>,
+}
+
+impl<T: Send> NotifyQueue<T> {
+    /// Creates a new queue with no callback.
+    pub fn new() -> NotifyQueue<T> {
+        NotifyQueue { queue: Queue::new(), wake: None }
+    }
+
+    /// Sets the callback to invoke on each empty to non-empty transition.
+    ///
+    /// This must not race with any pushes; callbacks should be installed
+    /// before the queue is shared with producers.
+    pub fn set_wake_callback(&mut self, wake: ~Wake:Send) {
+        self.wake = Some(wake);
+    }
+
+    /// Pushes a node on to the queue, invoking the wake callback if the queue
+    /// was empty.
+    ///
+    /// See `Queue::push_was_empty` for how precise the transition detection
+    /// is.
+    pub unsafe fn push(&mut self, node: *mut Node<T>) {
+        if self.queue.push_was_empty(node) {
+            match self.wake {
+                Some(ref wake) => wake.wake(),
+                None => {}
+            }
+        }
+    }
+
+    /// Creates an eventfd and installs a callback which signals it, returning
+    /// the descriptor.
+    ///
+    /// The eventfd is non-blocking, and becomes readable on each transition
+    /// from empty to non-empty. Reading it resets it. The descriptor is owned
+    /// by the queue, and it is closed once the callback is replaced or the
+    /// queue is dropped. As with `set_wake_callback`, this must not race with
+    /// any pushes.
+    ///
+    /// The consumer must check `queue().is_empty()` after `pop` returns
+    /// `Empty` before polling the descriptor again, as described in the module
+    /// documentation; otherwise a node pushed while the last one was being
+    /// popped can be left on the queue with no readable event to announce it.
+    #[cfg(target_os = "linux")]
+    pub fn attach_eventfd(&mut self) -> libc::c_int {
+        let wake = EventFd::new();
+        let fd = wake.fd;
+        self.set_wake_callback(~wake as ~Wake:Send);
+        fd
+    }
+
+    /// Returns the underlying queue, for use by the consumer.
+    pub fn queue<'a>(&'a mut self) -> &'a mut Queue<T> { &mut self.queue }
+}
+
+#[cfg(target_os = "linux", target_arch = "mips")]
+static EFD_NONBLOCK: libc::c_int = 0x80;
+#[cfg(target_os = "linux", not(target_arch = "mips"))]
+static EFD_NONBLOCK: libc::c_int = 0x800;
+#[cfg(target_os = "linux")]
+static EFD_CLOEXEC: libc::c_int = 0x80000;
+
+// A wake callback which signals an eventfd, closing it when dropped
+#[cfg(target_os = "linux")]
+struct EventFd {
+    fd: libc::c_int,
+}
+
+#[cfg(target_os = "linux")]
+impl EventFd {
+    fn new() -> EventFd {
+        extern {
+            fn eventfd(initval: libc::c_uint, flags: libc::c_int) -> libc::c_int;
+        }
+        let fd = unsafe { eventfd(0, EFD_NONBLOCK | EFD_CLOEXEC) };
+        if fd < 0 {
+            fail!("failed to create an eventfd: {}", os::last_os_error());
+        }
+        EventFd { fd: fd }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Wake for EventFd {
+    fn wake(&self) {
+        // This only fails if the counter would overflow, in which case the
+        // eventfd is already readable.
+        let one = 1u64;
+        unsafe {
+            libc::write(self.fd, &one as *u64 as *libc::c_void,
+                        8 as libc::size_t);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for EventFd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd); }
+    }
+}
+
+/// An intrusive MPSC queue carrying scheduler data for its consumer.
+///
+/// The context is shared with every producer that invokes the wake callback,
+/// so it should only be mutated by the consumer while no producers can be
+/// pushing, or through interior atomics.
+///
+/// The consumer must follow the same protocol as for a `NotifyQueue` before
+/// waiting: an `Empty` from `poll` is only final once `queue().is_empty()`
+/// also returns `true`.
+pub struct ConsumerContext<T, C> {
+    priv queue: Queue<T>,
+    priv context: C,
+    priv wake: Option<~WakeWith<C>:Send>,
+}
+
+impl<T: Send, C: Send> ConsumerContext<T, C> {
+    /// Creates a new queue carrying `context`, with no callback.
+    pub fn new(context: C) -> ConsumerContext<T, C> {
+        ConsumerContext { queue: Queue::new(), context: context, wake: None }
+    }
+
+    /// Sets the callback to invoke on each empty to non-empty transition.
+    ///
+    /// As with `NotifyQueue`, this must not race with any pushes.
+    pub fn set_wake_callback(&mut self, wake: ~WakeWith<C>:Send) {
+        self.wake = Some(wake);
+    }
+
+    /// Pushes a node on to the queue, invoking the wake callback with the
+    /// consumer's context if the queue was empty.
+    pub unsafe fn push(&mut self, node: *mut Node<T>) {
+        if self.queue.push_was_empty(node) {
+            match self.wake {
+                Some(ref wake) => wake.wake(&self.context),
+                None => {}
+            }
+        }
+    }
+
+    /// Pops a node off of the queue, returning it along with the consumer's
+    /// context.
+    pub unsafe fn poll<'a>(&'a mut self) -> (PopResult<T>, &'a mut C) {
+        (self.queue.pop(), &mut self.context)
+    }
+
+    /// Returns the consumer's context.
+    pub fn context<'a>(&'a self) -> &'a C { &self.context }
+
+    /// Returns the underlying queue, for use by the consumer.
+    pub fn queue<'a>(&'a mut self) -> &'a mut Queue<T> { &mut self.queue }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomics;
+    use super::{NotifyQueue, Wake, ConsumerContext, WakeWith};
+    use sync::mpsc_intrusive::{Data, Empty, Paused};
+    use sync::mpsc_intrusive::test::{owned, free_owned, pop_owned};
+
+    static mut WAKEUPS: atomics::AtomicUint = atomics::INIT_ATOMIC_UINT;
+
+    struct Counter;
+    impl Wake for Counter {
+        fn wake(&self) { unsafe { WAKEUPS.fetch_add(1, atomics::SeqCst); } }
+    }
+
+    fn wakeups() -> uint { unsafe { WAKEUPS.load(atomics::SeqCst) } }
+
+    fn push(q: &mut NotifyQueue<uint>, i: uint) {
+        unsafe { q.push(owned(i)); }
+    }
+
+    fn pop(q: &mut NotifyQueue<uint>) -> uint {
+        pop_owned(q.queue()).unwrap()
+    }
+
+    #[test]
+    fn wakes_once_per_transition() {
+        let mut q = NotifyQueue::new();
+        q.set_wake_callback(~Counter as ~Wake:Send);
+
+        push(&mut q, 0);
+        push(&mut q, 1);
+        push(&mut q, 2);
+        assert_eq!(wakeups(), 1);
+        assert_eq!(pop(&mut q), 0);
+        assert_eq!(pop(&mut q), 1);
+        assert_eq!(pop(&mut q), 2);
+
+        push(&mut q, 3);
+        assert_eq!(wakeups(), 2);
+        push(&mut q, 4);
+        assert_eq!(pop(&mut q), 3);
+        push(&mut q, 5);
+        assert_eq!(wakeups(), 2);
+        assert_eq!(pop(&mut q), 4);
+        assert_eq!(pop(&mut q), 5);
+
+        push(&mut q, 6);
+        assert_eq!(wakeups(), 3);
+        assert_eq!(pop(&mut q), 6);
+    }
+
+    #[test]
+    fn wake_sees_context() {
+        static mut WOKEN_SLOT: atomics::AtomicUint = atomics::INIT_ATOMIC_UINT;
+
+        struct Slot { id: uint }
+        struct Reactor;
+        impl WakeWith<Slot> for Reactor {
+            fn wake(&self, slot: &Slot) {
+                unsafe { WOKEN_SLOT.store(slot.id, atomics::SeqCst); }
+            }
+        }
+
+        let mut q: ConsumerContext<uint, Slot> =
+            ConsumerContext::new(Slot { id: 7 });
+        q.set_wake_callback(~Reactor as ~WakeWith<Slot>:Send);
+        unsafe {
+            q.push(owned(1u));
+            assert_eq!(WOKEN_SLOT.load(atomics::SeqCst), 7);
+        }
+
+        match unsafe { q.poll() } {
+            (Data(node), slot) => {
+                assert_eq!(free_owned(node), 1);
+                slot.id = 8;
+            }
+            (Empty, _) | (Paused, _) => fail!(),
+        }
+        assert_eq!(q.context().id, 8);
+
+        unsafe {
+            q.push(owned(2u));
+            assert_eq!(WOKEN_SLOT.load(atomics::SeqCst), 8);
+            match q.poll() {
+                (Data(node), _) => { free_owned(node); }
+                (Empty, _) | (Paused, _) => fail!(),
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn eventfd() {
+        use std::libc;
+
+        fn readable(fd: libc::c_int) -> bool {
+            let mut n = 0u64;
+            let ret = unsafe {
+                libc::read(fd, &mut n as *mut u64 as *mut libc::c_void,
+                           8 as libc::size_t)
+            };
+            if ret == 8 { assert!(n > 0); true } else { false }
+        }
+
+        let mut q = NotifyQueue::new();
+        let fd = q.attach_eventfd();
+        assert!(!readable(fd));
+
+        push(&mut q, 0);
+        push(&mut q, 1);
+        assert!(readable(fd));
+        assert!(!readable(fd));
+
+        assert_eq!(pop(&mut q), 0);
+        assert_eq!(pop(&mut q), 1);
+        push(&mut q, 2);
+        assert!(readable(fd));
+        assert_eq!(pop(&mut q), 2);
+    }
+}
@@ -0,0 +1,117 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! An intrusive queue at a stable address, for sharing with foreign code.
+//!
+//! Once anything has been popped off of a `Queue` it holds pointers into
+//! itself, so it must not move. A `PinnedQueue` allocates its queue on the
+//! heap and frees it once the last reference goes away, and it can hand out
+//! references as raw pointers which stay valid in the meantime, such as for
+//! passing to C or to another runtime.
+
+#[experimental];
+
+use std::cast;
+
+use sync::mpsc_intrusive::Queue;
+use sync::refcount::AtomicRefCount;
+
+// The queue comes first, so that a pointer to it is a pointer to the whole.
+struct Inner<T> {
+    queue: Queue<T>,
+    refs: AtomicRefCount,
+}
+
+/// A counted reference to a heap-allocated intrusive queue.
+///
+/// Cloning a `PinnedQueue` adds a reference. The queue is freed when the last
+/// reference is dropped; nodes still on it at that point are not freed.
+pub struct PinnedQueue<T> {
+    priv inner: *mut Inner<T>,
+}
+
+impl<T: Send> PinnedQueue<T> {
+    /// Allocates a new queue, returning the only reference to it.
+    pub fn new() -> PinnedQueue<T> {
+        let inner = ~Inner { queue: Queue::new(), refs: AtomicRefCount::new(1) };
+        PinnedQueue { inner: unsafe { cast::transmute(inner) } }
+    }
+
+    /// Returns the queue. The usual rules for using an intrusive queue apply:
+    /// any number of producers, but only one consumer.
+    pub fn get(&self) -> *mut Queue<T> {
+        self.inner as *mut Queue<T>
+    }
+
+    /// Converts this reference into a raw pointer to the queue, which stays
+    /// valid until it is converted back with `from_raw`.
+    pub fn into_raw(self) -> *mut Queue<T> {
+        let ret = self.get();
+        unsafe { cast::forget(self); }
+        ret
+    }
+
+    /// Converts a pointer returned by `into_raw` back into a reference. Each
+    /// such pointer must be converted back exactly once.
+    pub unsafe fn from_raw(q: *mut Queue<T>) -> PinnedQueue<T> {
+        PinnedQueue { inner: q as *mut Inner<T> }
+    }
+}
+
+impl<T: Send> Clone for PinnedQueue<T> {
+    fn clone(&self) -> PinnedQueue<T> {
+        unsafe { (*self.inner).refs.inc(); }
+        PinnedQueue { inner: self.inner }
+    }
+}
+
+#[unsafe_destructor]
+impl<T: Send> Drop for PinnedQueue<T> {
+    fn drop(&mut self) {
+        unsafe {
+            if (*self.inner).refs.dec() {
+                let _: ~Inner<T> = cast::transmute(self.inner);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate native;
+    use super::PinnedQueue;
+    use sync::mpsc_intrusive::Queue;
+    use sync::mpsc_intrusive::test::{owned, pop_owned};
+
+    #[test]
+    fn raw_handle() {
+        let q = PinnedQueue::new();
+        let raw = q.clone().into_raw() as uint;
+        let (tx, rx) = channel();
+        native::task::spawn(proc() {
+            let raw = raw as *mut Queue<uint>;
+            unsafe {
+                for i in range(0u, 10) {
+                    (*raw).push(owned(i));
+                }
+                drop(PinnedQueue::from_raw(raw));
+            }
+            tx.send(());
+        });
+        rx.recv();
+
+        let addr = q.get();
+        assert_eq!(addr as uint, raw);
+        for i in range(0u, 10) {
+            assert_eq!(pop_owned(unsafe { &mut *addr }), Some(i));
+        }
+        unsafe { assert_eq!((*q.inner).refs.count(), 1); }
+    }
+}
@@ -0,0 +1,111 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! An intrusive queue which lets its consumer intercept popped nodes.
+//!
+//! Consumers which pool their nodes for reuse would otherwise have to route
+//! every popped node through the pool by hand. A `ReclaimQueue` instead shows
+//! each node to a hook right before `pop` hands it back, so that node
+//! lifecycle management lives in one place.
+
+#[experimental];
+
+use sync::mpsc_intrusive::{Queue, Node, PopResult, Data, Empty, Paused};
+
+/// A hook invoked on every node popped off of a `ReclaimQueue`.
+///
+/// The hook is run on the consumer, and it must not free the node: the node is
+/// still handed back by `pop` once the hook returns.
+pub trait Reclaim<T> {
+    fn reclaim(&mut self, node: *mut Node<T>);
+}
+
+/// An intrusive MPSC queue with an optional reclaim hook.
+pub struct ReclaimQueue<T> {
+    priv queue: Queue<T>,
+    priv hook: Option<~Reclaim<T>:Send>,
+}
+
+impl<T: Send> ReclaimQueue<T> {
+    /// Creates a new queue with no hook.
+    pub fn new() -> ReclaimQueue<T> {
+        ReclaimQueue { queue: Queue::new(), hook: None }
+    }
+
+    /// Sets the hook to invoke on each popped node.
+    pub fn set_reclaim_hook(&mut self, hook: ~Reclaim<T>:Send) {
+        self.hook = Some(hook);
+    }
+
+    /// Pushes a node on to the queue.
+    pub unsafe fn push(&mut self, node: *mut Node<T>) {
+        self.queue.push(node)
+    }
+
+    /// Pops a node off of the queue, showing it to the reclaim hook first.
+    pub unsafe fn pop(&mut self) -> PopResult<T> {
+        match self.queue.pop() {
+            Data(node) => {
+                match self.hook {
+                    Some(ref mut hook) => hook.reclaim(node),
+                    None => {}
+                }
+                Data(node)
+            }
+            Empty => Empty,
+            Paused => Paused,
+        }
+    }
+
+    /// Returns the underlying queue.
+    pub fn queue<'a>(&'a mut self) -> &'a mut Queue<T> { &mut self.queue }
+}
+
+#[cfg(test)]
+mod test {
+    use std::comm;
+    use super::{ReclaimQueue, Reclaim};
+    use sync::mpsc_intrusive::{Node, Data, Empty, Paused};
+    use sync::mpsc_intrusive::test::{owned, free_owned};
+
+    struct Recorder { tx: Sender<uint> }
+    impl Reclaim<uint> for Recorder {
+        fn reclaim(&mut self, node: *mut Node<uint>) {
+            self.tx.send(unsafe { (*node).data });
+        }
+    }
+
+    fn drained(rx: &Receiver<uint>) -> bool {
+        match rx.try_recv() { comm::Empty => true, _ => false }
+    }
+
+    #[test]
+    fn hook_sees_every_node() {
+        let (tx, rx) = channel();
+        let mut q = ReclaimQueue::new();
+        q.set_reclaim_hook(~Recorder { tx: tx } as ~Reclaim<uint>:Send);
+        for i in range(0u, 10) {
+            unsafe { q.push(owned(i)); }
+        }
+        for i in range(0u, 10) {
+            match unsafe { q.pop() } {
+                Data(node) => {
+                    // the hook has already seen this node, and no other
+                    assert_eq!(rx.recv(), i);
+                    assert!(drained(&rx));
+                    assert_eq!(free_owned(node), i);
+                }
+                Empty | Paused => fail!(),
+            }
+        }
+        match unsafe { q.pop() } { Empty => {} _ => fail!() }
+        assert!(drained(&rx));
+    }
+}
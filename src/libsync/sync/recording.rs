@@ -0,0 +1,203 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! An intrusive queue which logs every operation performed on it, for tests.
+//!
+//! A `RecordingQueue` stamps each push and pop with the thread which performed
+//! it and a logical time, so that a test can inspect the interleaving which a
+//! concurrent workload actually ran with after the fact. The log is appended
+//! to under a lock, which serializes the workload far more than the queue
+//! itself does, so this is only compiled for tests.
+
+#[allow(dead_code)];
+
+use std::local_data;
+use std::mem;
+use std::sync::atomics;
+use std::vec_ng::Vec;
+
+use sync::mpsc_intrusive::{TrackedQueue, Node, SendError, PopResult, Data};
+use sync::mutex::Mutex;
+
+local_data_key!(THREAD_ID: uint)
+
+static mut NEXT_THREAD_ID: atomics::AtomicUint = atomics::INIT_ATOMIC_UINT;
+
+#[deriving(Eq, Show)]
+pub enum OpKind {
+    Pushed,
+    /// A push which the queue rejected because it had been closed.
+    Rejected,
+    Popped,
+}
+
+/// One recorded operation.
+pub struct Op {
+    kind: OpKind,
+    /// An id for the thread which performed the operation, unique within this
+    /// process.
+    thread: uint,
+    /// The position of this operation in the log.
+    time: uint,
+    /// The address of the node pushed or popped.
+    node: uint,
+}
+
+/// An intrusive MPSC queue which records a log of its operations.
+pub struct RecordingQueue<T> {
+    priv queue: TrackedQueue<T>,
+    priv lock: Mutex,
+    priv log: Vec<Op>,
+}
+
+impl<T: Send> RecordingQueue<T> {
+    pub fn new() -> RecordingQueue<T> {
+        RecordingQueue {
+            queue: TrackedQueue::new(),
+            lock: Mutex::new(),
+            log: Vec::new(),
+        }
+    }
+
+    /// Pushes a node on to the queue, as `TrackedQueue::push` does.
+    ///
+    /// The push is logged before it's performed, so that it always precedes
+    /// the pop of the same node in the log.
+    pub unsafe fn push(&mut self, node: *mut Node<T>)
+                       -> Result<(), SendError<T>> {
+        self.record(Pushed, node);
+        let ret = self.queue.push(node);
+        if ret.is_err() { self.record(Rejected, node); }
+        ret
+    }
+
+    /// Pops a node off of the queue, as `TrackedQueue::pop` does. Only pops which
+    /// return a node are logged.
+    pub unsafe fn pop(&mut self) -> PopResult<T> {
+        let ret = self.queue.pop();
+        match ret {
+            Data(node) => self.record(Popped, node),
+            _ => {}
+        }
+        ret
+    }
+
+    /// Closes the underlying queue.
+    pub fn close(&mut self) { self.queue.close() }
+
+    /// Returns all of the operations logged so far, in the order in which
+    /// they were logged, and starts a new log.
+    pub fn take_log(&mut self) -> Vec<Op> {
+        let _g = self.lock.lock();
+        mem::replace(&mut self.log, Vec::new())
+    }
+
+    fn record(&mut self, kind: OpKind, node: *mut Node<T>) {
+        let thread = thread_id();
+        let _g = self.lock.lock();
+        let time = self.log.len();
+        self.log.push(Op { kind: kind, thread: thread, time: time,
+                           node: node as uint });
+    }
+}
+
+// Returns the id of the current thread, assigning it on first use.
+fn thread_id() -> uint {
+    match local_data::get(THREAD_ID, |id| id.map(|id| *id)) {
+        Some(id) => id,
+        None => {
+            let id = unsafe { NEXT_THREAD_ID.fetch_add(1, atomics::SeqCst) };
+            local_data::set(THREAD_ID, id);
+            id
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate native;
+
+    use std::sync::arc::UnsafeArc;
+    use std::vec_ng::Vec;
+    use super::{RecordingQueue, Pushed, Rejected, Popped};
+    use sync::mpsc_intrusive::{Data, Empty, Paused};
+    use sync::mpsc_intrusive::test::{owned, free_owned};
+
+    #[test]
+    fn log_is_consistent() {
+        static PRODUCERS: uint = 4;
+        static PUSHES: uint = 100;
+
+        let q: UnsafeArc<RecordingQueue<uint>> =
+            UnsafeArc::new(RecordingQueue::new());
+        let (tx, rx) = channel();
+        for _ in range(0, PRODUCERS) {
+            let q = q.clone();
+            let tx = tx.clone();
+            native::task::spawn(proc() {
+                for i in range(0, PUSHES) {
+                    unsafe { assert!((*q.get()).push(owned(i)).is_ok()); }
+                }
+                tx.send(());
+            });
+        }
+
+        // Nodes are only freed once the log has been checked, so that no two
+        // pushes in the log have the same address.
+        let mut nodes = Vec::new();
+        while nodes.len() < PRODUCERS * PUSHES {
+            match unsafe { (*q.get()).pop() } {
+                Data(node) => nodes.push(node),
+                Empty | Paused => {}
+            }
+        }
+        for _ in range(0, PRODUCERS) { rx.recv(); }
+        unsafe {
+            (*q.get()).close();
+            assert!((*q.get()).push(owned(0u)).is_err());
+        }
+
+        let log = unsafe { (*q.get()).take_log() };
+        let consumer = log.iter().find(|op| op.kind == Popped).unwrap().thread;
+        let mut outstanding = Vec::new();
+        let (mut pushed, mut popped, mut rejected) = (0u, 0u, 0u);
+        for (i, op) in log.iter().enumerate() {
+            assert_eq!(op.time, i);
+            match op.kind {
+                Pushed => {
+                    assert!(!outstanding.contains(&op.node));
+                    outstanding.push(op.node);
+                    pushed += 1;
+                }
+                Popped => {
+                    assert_eq!(op.thread, consumer);
+                    let pos = outstanding.iter().position(|&n| n == op.node);
+                    assert!(pos.is_some(), "popped a node which wasn't pushed");
+                    outstanding.swap_remove(pos.unwrap());
+                    popped += 1;
+                }
+                Rejected => {
+                    assert_eq!(op.thread, consumer);
+                    let pos = outstanding.iter().position(|&n| n == op.node);
+                    outstanding.swap_remove(pos.unwrap());
+                    free_owned(op.node);
+                    rejected += 1;
+                }
+            }
+        }
+        assert!(outstanding.is_empty());
+        assert_eq!(pushed, PRODUCERS * PUSHES + 1);
+        assert_eq!(popped, PRODUCERS * PUSHES);
+        assert_eq!(rejected, 1);
+        assert!(unsafe { (*q.get()).take_log() }.is_empty());
+
+        for &node in nodes.iter() { free_owned(node); }
+    }
+}
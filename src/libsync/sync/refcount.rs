@@ -0,0 +1,93 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! An atomic reference count, for structures shared between tasks which free
+//! themselves once the last reference goes away.
+
+use std::sync::atomics;
+
+/// A count of the references to some shared structure.
+pub struct AtomicRefCount {
+    priv count: atomics::AtomicUint,
+}
+
+impl AtomicRefCount {
+    /// Creates a count with `n` references.
+    pub fn new(n: uint) -> AtomicRefCount {
+        AtomicRefCount { count: atomics::AtomicUint::new(n) }
+    }
+
+    /// Adds a reference. This must only be called by a holder of an existing
+    /// reference.
+    #[inline]
+    pub fn inc(&mut self) {
+        // A new reference can only be made from an existing one, so there is
+        // nothing to synchronize with here.
+        self.count.fetch_add(1, atomics::Relaxed);
+    }
+
+    /// Drops a reference, returning whether it was the last one. If it was,
+    /// the caller may free the shared structure.
+    #[inline]
+    pub fn dec(&mut self) -> bool {
+        // Every holder's accesses to the structure must happen before it is
+        // freed: each decrement releases them, and the final decrement
+        // acquires them all with its fence.
+        if self.count.fetch_sub(1, atomics::Release) != 1 { return false }
+        atomics::fence(atomics::Acquire);
+        true
+    }
+
+    /// Returns the current number of references. This is racy unless the
+    /// caller knows that no other references are being made or dropped.
+    pub fn count(&self) -> uint {
+        self.count.load(atomics::SeqCst)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    extern crate native;
+    use std::sync::arc::UnsafeArc;
+    use std::sync::atomics;
+    use super::AtomicRefCount;
+
+    #[test]
+    fn last_dec_happens_once() {
+        static NTHREADS: uint = 8;
+        static mut ZEROES: atomics::AtomicUint = atomics::INIT_ATOMIC_UINT;
+
+        let rc = UnsafeArc::new(AtomicRefCount::new(1));
+        let (tx, rx) = channel();
+        for _ in range(0, NTHREADS) {
+            unsafe { (*rc.get()).inc(); }
+            let rc = rc.clone();
+            let tx = tx.clone();
+            native::task::spawn(proc() {
+                unsafe {
+                    for _ in range(0, 1000) {
+                        (*rc.get()).inc();
+                        assert!(!(*rc.get()).dec());
+                    }
+                    if (*rc.get()).dec() { ZEROES.fetch_add(1, atomics::SeqCst); }
+                }
+                tx.send(());
+            });
+        }
+        unsafe {
+            if (*rc.get()).dec() { ZEROES.fetch_add(1, atomics::SeqCst); }
+        }
+        for _ in range(0, NTHREADS) { rx.recv(); }
+        unsafe {
+            assert_eq!(ZEROES.load(atomics::SeqCst), 1);
+            assert_eq!((*rc.get()).count(), 0);
+        }
+    }
+}
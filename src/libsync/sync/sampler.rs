@@ -0,0 +1,113 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A bounded history of a queue's depth.
+//!
+//! None of the queues in this crate keep track of their length, so a
+//! `DepthSampler` counts pushes and pops as they're reported to it, recording
+//! the resulting depth after every one in a fixed-size ring. The ring keeps
+//! the most recent samples, which can be used to graph a queue's backlog over
+//! time with bounded memory.
+
+use std::sync::atomics;
+use std::vec_ng::Vec;
+
+/// A ring of the most recent depths of a queue.
+pub struct DepthSampler {
+    priv ring: Vec<atomics::AtomicUint>,
+    // The total number of samples ever recorded
+    priv recorded: atomics::AtomicUint,
+    priv depth: atomics::AtomicUint,
+}
+
+impl DepthSampler {
+    /// Creates a sampler which keeps the last `n` samples.
+    pub fn new(n: uint) -> DepthSampler {
+        assert!(n > 0);
+        DepthSampler {
+            ring: range(0, n).map(|_| atomics::AtomicUint::new(0)).collect(),
+            recorded: atomics::AtomicUint::new(0),
+            depth: atomics::AtomicUint::new(0),
+        }
+    }
+
+    /// Reports a push, recording the new depth.
+    pub fn pushed(&mut self) {
+        let depth = self.depth.fetch_add(1, atomics::SeqCst) + 1;
+        self.record(depth);
+    }
+
+    /// Reports a pop, recording the new depth.
+    ///
+    /// A pop reported by one task may be counted before the matching push is
+    /// reported by another, so the depth saturates at zero rather than
+    /// wrapping around.
+    pub fn popped(&mut self) {
+        let mut old = self.depth.load(atomics::SeqCst);
+        loop {
+            let new = if old == 0 { 0 } else { old - 1 };
+            let prev = self.depth.compare_and_swap(old, new, atomics::SeqCst);
+            if prev == old {
+                self.record(new);
+                return
+            }
+            old = prev;
+        }
+    }
+
+    fn record(&mut self, depth: uint) {
+        let i = self.recorded.fetch_add(1, atomics::SeqCst) % self.ring.len();
+        self.ring.get_mut(i).store(depth, atomics::Relaxed);
+    }
+
+    /// Returns the recorded samples, oldest first.
+    ///
+    /// If pushes or pops are reported concurrently with this call, the most
+    /// recent samples may be missing or out of date.
+    pub fn samples(&self) -> Vec<uint> {
+        let n = self.ring.len();
+        let recorded = self.recorded.load(atomics::SeqCst);
+        let start = if recorded > n { recorded - n } else { 0 };
+        range(start, recorded).map(|i| {
+            self.ring.get(i % n).load(atomics::Relaxed)
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::vec_ng::Vec;
+    use super::DepthSampler;
+
+    #[test]
+    fn trajectory() {
+        let mut s = DepthSampler::new(4);
+        assert!(s.samples().is_empty());
+        s.pushed();
+        s.pushed();
+        assert!(s.samples() == Vec::from_slice(&[1u, 2]));
+        s.pushed();
+        s.popped();
+        s.pushed();
+        s.pushed();
+        // only the last four are kept
+        assert!(s.samples() == Vec::from_slice(&[3u, 2, 3, 4]));
+        for _ in range(0, 4) { s.popped(); }
+        assert!(s.samples() == Vec::from_slice(&[3u, 2, 1, 0]));
+    }
+
+    #[test]
+    fn pop_before_push() {
+        let mut s = DepthSampler::new(4);
+        s.popped();
+        s.pushed();
+        assert!(s.samples() == Vec::from_slice(&[0u, 1]));
+    }
+}
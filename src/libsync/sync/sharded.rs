@@ -0,0 +1,146 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Lock-protected state split into shards.
+//!
+//! State which is protected by a single lock serializes every task which
+//! touches it. When accesses can be partitioned by some key, a `Sharded`
+//! value splits the state into several independently locked shards and
+//! hashes each key to one of them, so that tasks working on different keys
+//! rarely contend.
+
+use std::hash;
+use std::hash::Hash;
+use std::vec_ng::Vec;
+
+use arc::{MutexArc, RWArc};
+
+/// A shared lock which can protect one shard of a `Sharded` value.
+///
+/// This is implemented for `MutexArc`, and for `RWArc` so that read-mostly
+/// shards can be read concurrently through `Sharded::read_shard`.
+pub trait ShardLock<T>: Clone {
+    /// Creates a lock protecting `data`.
+    fn new_shard(data: T) -> Self;
+
+    /// Runs a function with exclusive access to the protected data.
+    fn access_shard<U>(&self, blk: |&mut T| -> U) -> U;
+}
+
+impl<T: Send> ShardLock<T> for MutexArc<T> {
+    fn new_shard(data: T) -> MutexArc<T> { MutexArc::new(data) }
+    fn access_shard<U>(&self, blk: |&mut T| -> U) -> U { self.access(blk) }
+}
+
+impl<T: Freeze + Send> ShardLock<T> for RWArc<T> {
+    fn new_shard(data: T) -> RWArc<T> { RWArc::new(data) }
+    fn access_shard<U>(&self, blk: |&mut T| -> U) -> U { self.write(blk) }
+}
+
+/// A set of shards of type `T`, each protected by its own lock of type `L`.
+///
+/// Cloning a `Sharded` creates a new handle to the same shards.
+pub struct Sharded<T, L> {
+    priv shards: Vec<L>,
+}
+
+impl<T: Send, L: ShardLock<T>> Sharded<T, L> {
+    /// Creates `n` shards, with shard `i` initialized to `init(i)`.
+    pub fn new(n: uint, init: |uint| -> T) -> Sharded<T, L> {
+        assert!(n > 0);
+        Sharded {
+            shards: range(0, n).map(|i| ShardLock::new_shard(init(i))).collect()
+        }
+    }
+
+    /// Returns the number of shards.
+    pub fn len(&self) -> uint { self.shards.len() }
+
+    /// Returns the index of the shard which `key` maps to.
+    pub fn shard_for<K: Hash>(&self, key: &K) -> uint {
+        (hash::hash(key) % self.shards.len() as u64) as uint
+    }
+
+    /// Runs a function with exclusive access to the shard which `key` maps
+    /// to. Every access with an equal key is to the same shard.
+    pub fn with_shard<K: Hash, U>(&self, key: &K, blk: |&mut T| -> U) -> U {
+        self.shards.get(self.shard_for(key)).access_shard(blk)
+    }
+}
+
+impl<T: Freeze + Send> Sharded<T, RWArc<T>> {
+    /// Runs a function with shared access to the shard which `key` maps to,
+    /// concurrently with any other readers of that shard.
+    pub fn read_shard<K: Hash, U>(&self, key: &K, blk: |&T| -> U) -> U {
+        self.shards.get(self.shard_for(key)).read(blk)
+    }
+}
+
+impl<T: Send, L: ShardLock<T>> Clone for Sharded<T, L> {
+    fn clone(&self) -> Sharded<T, L> {
+        Sharded { shards: self.shards.iter().map(|s| s.clone()).collect() }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use arc::{MutexArc, RWArc};
+    use super::Sharded;
+
+    #[test]
+    fn same_key_same_shard() {
+        let s: Sharded<(uint, uint), MutexArc<(uint, uint)>> =
+            Sharded::new(8, |i| (i, 0u));
+        for _ in range(0, 3) {
+            s.with_shard(&42u, |shard| {
+                let (_, ref mut n) = *shard;
+                *n += 1;
+            });
+        }
+        let idx = s.shard_for(&42u);
+        s.with_shard(&42u, |shard| {
+            let (i, n) = *shard;
+            assert_eq!(i, idx);
+            assert_eq!(n, 3);
+        });
+    }
+
+    #[test]
+    fn keys_spread() {
+        let s: Sharded<uint, MutexArc<uint>> = Sharded::new(8, |_| 0u);
+        for key in range(0u, 100) {
+            s.with_shard(&key, |n| *n += 1);
+        }
+        let mut used = 0;
+        let mut total = 0;
+        for i in range(0u, 8) {
+            // find a key which maps to shard i, if any did
+            match range(0u, 100).find(|k| s.shard_for(k) == i) {
+                Some(key) => s.with_shard(&key, |n| {
+                    used += 1;
+                    total += *n;
+                }),
+                None => {}
+            }
+        }
+        assert!(used > 1);
+        assert_eq!(total, 100);
+    }
+
+    #[test]
+    fn rwarc_shards() {
+        let s: Sharded<uint, RWArc<uint>> = Sharded::new(4, |i| i * 10);
+        let idx = s.shard_for(&"key");
+        s.read_shard(&"key", |n| assert_eq!(*n, idx * 10));
+        s.with_shard(&"key", |n| *n += 1);
+        let s2 = s.clone();
+        s2.read_shard(&"key", |n| assert_eq!(*n, idx * 10 + 1));
+    }
+}
@@ -0,0 +1,141 @@
+// Copyright 2014 The Rust Project Developers. See the COPYRIGHT
+// file at the top-level directory of this distribution and at
+// http://rust-lang.org/COPYRIGHT.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! A bounded, blocking, last-in-first-out channel.
+//!
+//! Unlike a regular channel, values are delivered most-recently-pushed first,
+//! which is handy for depth-first work scheduling.
+
+use std::vec_ng::Vec;
+
+use arc::MutexArc;
+use sync::Semaphore;
+
+/// A bounded LIFO channel which can be shared among many tasks.
+///
+/// Pushing blocks the current task while the channel is full and popping
+/// blocks the current task while the channel is empty. Cloning a `StackChan`
+/// yields another handle to the same underlying stack.
+///
+/// # Example
+///
+/// ```rust
+/// use sync::stack_chan::StackChan;
+///
+/// let chan = StackChan::new(2);
+/// chan.push(1);
+/// chan.push(2);
+/// assert_eq!(chan.pop(), 2);
+/// assert_eq!(chan.pop(), 1);
+/// ```
+pub struct StackChan<T> {
+    priv stack: MutexArc<Vec<T>>,
+    /// Counts the number of free slots on the stack
+    priv slots: Semaphore,
+    /// Counts the number of values on the stack
+    priv items: Semaphore,
+}
+
+impl<T: Send> StackChan<T> {
+    /// Creates a new channel which can hold at most `capacity` values.
+    pub fn new(capacity: uint) -> StackChan<T> {
+        assert!(capacity > 0);
+        StackChan {
+            stack: MutexArc::new(Vec::with_capacity(capacity)),
+            slots: Semaphore::new(capacity as int),
+            items: Semaphore::new(0),
+        }
+    }
+
+    /// Pushes a value on to the top of the stack, blocking the current task
+    /// until there is room for it.
+    pub fn push(&self, t: T) {
+        self.slots.acquire();
+        let mut t = Some(t);
+        self.stack.access(|stack| stack.push(t.take_unwrap()));
+        self.items.release();
+    }
+
+    /// Pops the most recently pushed value off of the stack, blocking the
+    /// current task until one is available.
+    pub fn pop(&self) -> T {
+        self.items.acquire();
+        let t = self.stack.access(|stack| stack.pop().unwrap());
+        self.slots.release();
+        t
+    }
+}
+
+impl<T: Send> Clone for StackChan<T> {
+    fn clone(&self) -> StackChan<T> {
+        StackChan {
+            stack: self.stack.clone(),
+            slots: self.slots.clone(),
+            items: self.items.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::StackChan;
+    use std::comm::Empty;
+    use std::task;
+
+    #[test]
+    fn lifo() {
+        let chan = StackChan::new(3);
+        chan.push(1);
+        chan.push(2);
+        chan.push(3);
+        assert_eq!(chan.pop(), 3);
+        chan.push(4);
+        assert_eq!(chan.pop(), 4);
+        assert_eq!(chan.pop(), 2);
+        assert_eq!(chan.pop(), 1);
+    }
+
+    #[test]
+    fn push_blocks_when_full() {
+        let chan = StackChan::new(2);
+        let chan2 = chan.clone();
+        let (tx, rx) = channel();
+        spawn(proc() {
+            for i in range(0, 3) {
+                chan2.push(i);
+                tx.send(i);
+            }
+        });
+        assert_eq!(rx.recv(), 0);
+        assert_eq!(rx.recv(), 1);
+        for _ in range(0, 10) { task::deschedule() }
+        assert!(rx.try_recv() == Empty);
+
+        assert_eq!(chan.pop(), 1);
+        assert_eq!(rx.recv(), 2);
+        assert_eq!(chan.pop(), 2);
+        assert_eq!(chan.pop(), 0);
+    }
+
+    #[test]
+    fn pop_blocks_when_empty() {
+        let chan = StackChan::new(2);
+        let chan2 = chan.clone();
+        let (tx, rx) = channel();
+        spawn(proc() {
+            tx.send(chan2.pop());
+        });
+        for _ in range(0, 10) { task::deschedule() }
+        assert!(rx.try_recv() == Empty);
+
+        chan.push(5);
+        assert_eq!(rx.recv(), 5);
+    }
+}